@@ -0,0 +1,274 @@
+use std::{ffi::CString, fs::{self, OpenOptions}, io::{self, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, sync::OnceLock};
+use serde::{Serialize, Deserialize};
+use libc::{O_WRONLY, O_CLOEXEC};
+use crate::task_utils::{Config, parse_size};
+
+const CONTROLLERS: [&str; 4] = ["memory", "cpu", "pids", "io"];
+const CPU_PERIOD: u64 = 100000;
+
+// Where task cgroups are created, set up on first use.
+static TASKS_ROOT: OnceLock<Result<TasksRoot, String>> = OnceLock::new();
+
+#[derive(Debug)]
+struct TasksRoot {
+	// The supervisor's own cgroup when it started.
+	base: PathBuf,
+	tasks: PathBuf,
+	// Controllers setup_tasks_root turned on in base, to turn off again.
+	enabled: Vec<&'static str>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum CgroupValue {
+	Number(f64),
+	Text(String),
+}
+
+impl std::fmt::Display for CgroupValue {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			CgroupValue::Number(n) => write!(f, "{}", n),
+			CgroupValue::Text(s) => write!(f, "{}", s),
+		}
+	}
+}
+
+// Interface files to write when creating a cgroup for this config, e.g. ("memory.max", "536870912").
+pub fn settings(config: &Config) -> Result<Vec<(&'static str, String)>, String> {
+	let mut settings = vec![];
+	if let Some(value) = &config.memory_max {
+		settings.push(("memory.max", memory_value(value).map_err(|e| format!("memory_max: {}", e))?));
+	}
+	if let Some(value) = &config.cpu_max {
+		settings.push(("cpu.max", cpu_value(value).map_err(|e| format!("cpu_max: {}", e))?));
+	}
+	if let Some(value) = &config.pids_max {
+		settings.push(("pids.max", pids_value(value).map_err(|e| format!("pids_max: {}", e))?));
+	}
+	if let Some(weight) = config.io_weight {
+		if !(1..=10000).contains(&weight) {
+			return Err(format!("io_weight: {} is out of range (1 to 10000)", weight));
+		}
+		settings.push(("io.weight", format!("default {}", weight)));
+	}
+	Ok(settings)
+}
+
+pub fn is_enabled(config: &Config) -> bool {
+	config.cgroup_per_process || config.memory_max.is_some() || config.cpu_max.is_some()
+		|| config.pids_max.is_some() || config.io_weight.is_some()
+}
+
+// Bytes, or a string with a K/M/G/T suffix, or `max`.
+fn memory_value(value: &CgroupValue) -> Result<String, String> {
+	match value {
+		CgroupValue::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok((*n as u64).to_string()),
+		CgroupValue::Text(s) if s == "max" => Ok(s.clone()),
+		CgroupValue::Text(s) => parse_size(s).map(|n| n.to_string()),
+		_ => Err(format!("invalid size '{}'", value)),
+	}
+}
+
+// A number of CPUs (1.5), a percentage of one CPU ("150%"), or `max`.
+fn cpu_value(value: &CgroupValue) -> Result<String, String> {
+	let cpus = match value {
+		CgroupValue::Text(s) if s == "max" => return Ok(format!("max {}", CPU_PERIOD)),
+		CgroupValue::Text(s) => s.strip_suffix('%')
+			.and_then(|p| p.trim().parse::<f64>().ok())
+			.map(|p| p / 100.0)
+			.ok_or(format!("invalid cpu limit '{}'", s))?,
+		CgroupValue::Number(n) => *n,
+	};
+	let quota = (cpus * CPU_PERIOD as f64).round() as u64;
+	if cpus <= 0.0 || quota < 1000 {
+		return Err(format!("cpu limit '{}' is too small", value));
+	}
+	Ok(format!("{} {}", quota, CPU_PERIOD))
+}
+
+fn pids_value(value: &CgroupValue) -> Result<String, String> {
+	match value {
+		CgroupValue::Number(n) if *n >= 1.0 && n.fract() == 0.0 => Ok((*n as u64).to_string()),
+		CgroupValue::Text(s) if s == "max" => Ok(s.clone()),
+		_ => Err(format!("invalid process count '{}'", value)),
+	}
+}
+
+#[derive(Debug)]
+pub struct Cgroup {
+	pub path: PathBuf,
+	procs: CString,
+	oom_kills: u64,
+}
+
+impl Cgroup {
+	pub fn for_task(name: &str) -> Result<Cgroup, String> {
+		let root = TASKS_ROOT.get_or_init(setup_tasks_root).as_ref().map_err(|e| e.clone())?;
+		Cgroup::create(root.tasks.join(name))
+	}
+
+	pub fn child(&self, name: &str) -> Result<Cgroup, String> {
+		enable_controllers(&self.path);
+		Cgroup::create(self.path.join(name))
+	}
+
+	fn create(path: PathBuf) -> Result<Cgroup, String> {
+		if let Err(e) = fs::create_dir(&path) {
+			if e.kind() != io::ErrorKind::AlreadyExists {
+				return Err(format!("cgroup {}: {}", path.display(), e));
+			}
+		}
+		let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+		let mut cgroup = Cgroup { path, procs, oom_kills: 0 };
+		cgroup.oom_kills = cgroup.read_key("memory.events", "oom_kill").unwrap_or(0);
+		Ok(cgroup)
+	}
+
+	pub fn apply(&self, settings: &[(&str, String)]) -> Result<(), String> {
+		for (file, value) in settings {
+			// No create(): interface files of a disabled controller simply do not exist.
+			let written = OpenOptions::new().write(true).open(self.path.join(file))
+				.and_then(|mut f| f.write_all(value.as_bytes()));
+			written.map_err(|e| match e.kind() {
+				io::ErrorKind::NotFound => format!("cgroup {}: controller for {} is not available", self.path.display(), file),
+				_ => format!("cgroup {}: {}: {}", self.path.display(), file, e),
+			})?;
+		}
+		Ok(())
+	}
+
+	// Path of cgroup.procs, for the child to move itself in before exec.
+	pub fn procs_path(&self) -> CString {
+		self.procs.clone()
+	}
+
+	// True if the OOM killer fired in this cgroup since the last call.
+	pub fn take_oom_kill(&mut self) -> bool {
+		let kills = self.read_key("memory.events", "oom_kill").unwrap_or(self.oom_kills);
+		let fired = kills > self.oom_kills;
+		self.oom_kills = kills;
+		fired
+	}
+
+	pub fn usage(&self) -> String {
+		let mut usage = vec![];
+		if let Ok(memory) = fs::read_to_string(self.path.join("memory.current")) {
+			if let Ok(bytes) = memory.trim().parse::<u64>() {
+				usage.push(format!("memory {:.1}M", bytes as f64 / (1 << 20) as f64));
+			}
+		}
+		if let Some(usec) = self.read_key("cpu.stat", "usage_usec") {
+			usage.push(format!("cpu {:.2}s", usec as f64 / 1_000_000.0));
+		}
+		if let Ok(pids) = fs::read_to_string(self.path.join("pids.current")) {
+			usage.push(format!("pids {}", pids.trim()));
+		}
+		if let Some(kills) = self.read_key("memory.events", "oom_kill") {
+			usage.push(format!("oom kills {}", kills));
+		}
+		usage.join(", ")
+	}
+
+	pub fn remove(&self) {
+		if let Err(e) = fs::remove_dir(&self.path) {
+			eprintln!("cgroup {}: {}", self.path.display(), e);
+		}
+	}
+
+	fn read_key(&self, file: &str, key: &str) -> Option<u64> {
+		fs::read_to_string(self.path.join(file)).ok()?
+			.lines()
+			.find_map(|line| line.strip_prefix(key)?.trim().parse().ok())
+	}
+}
+
+// Runs in the forked child: moves the calling process into the cgroup.
+pub fn join(procs: &CString) -> io::Result<()> {
+	let fd = unsafe { libc::open(procs.as_ptr(), O_WRONLY | O_CLOEXEC) };
+	if fd < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let ret = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+	let err = io::Error::last_os_error();
+	unsafe { libc::close(fd) };
+	if ret < 0 {
+		return Err(err);
+	}
+	Ok(())
+}
+
+// cgroup v2 forbids processes in a cgroup that hands controllers down to children,
+// so the supervisor moves itself into a leaf next to the task cgroups.
+fn setup_tasks_root() -> Result<TasksRoot, String> {
+	let base = own_cgroup().ok_or("cgroup v2 hierarchy not found")?;
+	let supervisor = base.join("supervisor");
+	let tasks = base.join("tasks");
+	for dir in [&supervisor, &tasks] {
+		if let Err(e) = fs::create_dir(dir) {
+			if e.kind() != io::ErrorKind::AlreadyExists {
+				return Err(format!("cgroup {}: {}", dir.display(), e));
+			}
+		}
+	}
+	fs::write(supervisor.join("cgroup.procs"), "0").map_err(|e| format!("cgroup {}: {}", supervisor.display(), e))?;
+	let enabled = enable_controllers(&base);
+	enable_controllers(&tasks);
+	Ok(TasksRoot { base, tasks, enabled })
+}
+
+// Undoes setup_tasks_root once the task cgroups are removed: the supervisor moves
+// back to the cgroup it started in, which first needs its controllers off again.
+pub fn remove_tasks_root() {
+	let Some(Ok(root)) = TASKS_ROOT.get() else { return };
+	let subtree_control = root.base.join("cgroup.subtree_control");
+	let removed = fs::remove_dir(&root.tasks)
+		.and_then(|_| root.enabled.iter().try_for_each(|controller| fs::write(&subtree_control, format!("-{}", controller))))
+		.and_then(|_| fs::write(root.base.join("cgroup.procs"), "0"))
+		.and_then(|_| fs::remove_dir(root.base.join("supervisor")));
+	if let Err(e) = removed {
+		eprintln!("cgroup {}: {}", root.base.display(), e);
+	}
+}
+
+// Returns the controllers that were not enabled before.
+fn enable_controllers(path: &Path) -> Vec<&'static str> {
+	let available = fs::read_to_string(path.join("cgroup.controllers")).unwrap_or_default();
+	let already = fs::read_to_string(path.join("cgroup.subtree_control")).unwrap_or_default();
+	let mut enabled = vec![];
+	for controller in CONTROLLERS.into_iter().filter(|c| available.split_whitespace().any(|a| a == *c)) {
+		if already.split_whitespace().any(|a| a == controller) {
+			continue;
+		}
+		// Best effort: a missing controller surfaces when its limit file is written.
+		if fs::write(path.join("cgroup.subtree_control"), format!("+{}", controller)).is_ok() {
+			enabled.push(controller);
+		}
+	}
+	enabled
+}
+
+// Task names become directories under the tasks root.
+pub fn validate_name(name: &str) -> Result<(), String> {
+	if name.is_empty() || name == "." || name.contains('/') || name.contains("..") {
+		return Err("cgroup: the task name cannot be empty or contain '/' or '..'".to_string());
+	}
+	Ok(())
+}
+
+// Directory of the supervisor's own cgroup in the mounted v2 hierarchy.
+fn own_cgroup() -> Option<PathBuf> {
+	let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+	let own = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+	let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+	mountinfo.lines().find_map(|line| {
+		let (mount, fs_type) = line.split_once(" - ")?;
+		if !fs_type.starts_with("cgroup2 ") {
+			return None;
+		}
+		let fields: Vec<&str> = mount.split_whitespace().collect();
+		let (root, mount_point) = (fields.get(3)?, fields.get(4)?);
+		let relative = own.strip_prefix(root)?.trim_start_matches('/');
+		Some(Path::new(mount_point).join(relative))
+	})
+}
@@ -0,0 +1,47 @@
+use std::{ffi::CString, io};
+use libc::{mode_t, PRIO_PROCESS, STDIN_FILENO, TIOCSCTTY};
+use crate::{cgroup, credentials::Credentials, rlimits::Rlimit};
+
+// Everything a child applies to itself between fork and exec, so nothing
+// here ever touches the supervisor's own process state.
+#[derive(Debug, Clone)]
+pub struct ChildSetup {
+	pub umask: mode_t,
+	pub nice: Option<i32>,
+	pub rlimits: Vec<Rlimit>,
+	pub credentials: Option<Credentials>,
+	// cgroup.procs of the cgroup the child moves itself into.
+	pub cgroup: Option<CString>,
+	// Make stdin, a pty slave, the controlling terminal of the new session.
+	pub controlling_tty: bool,
+}
+
+impl ChildSetup {
+	// Runs in the forked child from pre_exec: this and everything it calls may only
+	// make async-signal-safe calls.
+	pub fn apply(&self) -> io::Result<()> {
+		if let Some(procs) = &self.cgroup {
+			cgroup::join(procs)?;
+		}
+		unsafe { libc::umask(self.umask) };
+		if unsafe { libc::setsid() } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		if self.controlling_tty && unsafe { libc::ioctl(STDIN_FILENO, TIOCSCTTY, 0) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		if let Some(nice) = self.nice {
+			if unsafe { libc::setpriority(PRIO_PROCESS, 0, nice) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+		for rlimit in &self.rlimits {
+			rlimit.apply()?;
+		}
+		// Credentials last: dropping root first would forbid raising priority or hard limits.
+		if let Some(credentials) = &self.credentials {
+			credentials.apply()?;
+		}
+		Ok(())
+	}
+}
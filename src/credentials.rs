@@ -0,0 +1,152 @@
+use std::{ffi::{CStr, CString}, io, mem, ptr};
+use libc::{c_char, c_int, gid_t, uid_t, passwd, group, ERANGE};
+use crate::task_utils::Account;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+	pub uid: Option<uid_t>,
+	pub gid: Option<gid_t>,
+	pub groups: Vec<gid_t>,
+}
+
+struct UserEntry {
+	name: CString,
+	uid: uid_t,
+	gid: gid_t,
+}
+
+impl Credentials {
+	// Looks the accounts up once, in the supervisor, so the child only has to call set*id.
+	pub fn resolve(user: &Option<Account>, group: &Option<Account>, groups: &Option<Vec<Account>>) -> Result<Option<Credentials>, String> {
+		if user.is_none() && group.is_none() && groups.is_none() {
+			return Ok(None);
+		}
+		let (uid, entry) = match user {
+			Some(Account::Name(name)) => match (lookup_user(name)?, name.parse()) {
+				(Some(entry), _) => (Some(entry.uid), Some(entry)),
+				// A quoted number ("1000") with no account of that name is an id.
+				(None, Ok(id)) => (Some(id), lookup_user_by_id(id)?),
+				(None, Err(_)) => return Err(format!("user: no such user '{}'", name)),
+			},
+			Some(Account::Id(id)) => (Some(*id), lookup_user_by_id(*id)?),
+			None => (None, None),
+		};
+		let gid = match group {
+			Some(group) => Some(resolve_group(group).map_err(|e| format!("group: {}", e))?),
+			None => entry.as_ref().map(|e| e.gid),
+		};
+		if uid.is_some() && gid.is_none() {
+			return Err("user: uid has no passwd entry, set group explicitly".to_string());
+		}
+		let mut supplementary: Vec<gid_t> = match (groups, &entry, gid) {
+			(Some(groups), _, _) => groups.iter().map(resolve_group).collect::<Result<_, _>>().map_err(|e| format!("groups: {}", e))?,
+			(None, Some(entry), Some(gid)) => group_list(&entry.name, gid)?,
+			_ => vec![],
+		};
+		// Never let the child keep the supervisor's own supplementary groups.
+		if let Some(gid) = gid {
+			if !supplementary.contains(&gid) {
+				supplementary.insert(0, gid);
+			}
+		}
+		Ok(Some(Credentials { uid, gid, groups: supplementary }))
+	}
+
+	pub fn apply(&self) -> io::Result<()> {
+		// Only root may change the group list; an unprivileged supervisor can still run as itself.
+		let privileged = unsafe { libc::geteuid() } == 0;
+		if privileged && unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		if let Some(gid) = self.gid {
+			if unsafe { libc::setgid(gid) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+		if let Some(uid) = self.uid {
+			if unsafe { libc::setuid(uid) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+		Ok(())
+	}
+}
+
+fn resolve_group(group: &Account) -> Result<gid_t, String> {
+	match group {
+		Account::Id(id) => Ok(*id),
+		Account::Name(name) => match (lookup_group(name)?, name.parse()) {
+			(Some(gid), _) | (None, Ok(gid)) => Ok(gid),
+			(None, Err(_)) => Err(format!("no such group '{}'", name)),
+		},
+	}
+}
+
+fn lookup_user(name: &str) -> Result<Option<UserEntry>, String> {
+	let c_name = CString::new(name).map_err(|e| e.to_string())?;
+	with_buffer(|entry: &mut passwd, buf, result| unsafe {
+		libc::getpwnam_r(c_name.as_ptr(), entry, buf.as_mut_ptr(), buf.len(), result)
+	}).map(|entry| entry.map(|(pw, _buf)| UserEntry { name: unsafe { CStr::from_ptr(pw.pw_name) }.to_owned(), uid: pw.pw_uid, gid: pw.pw_gid }))
+}
+
+fn lookup_user_by_id(uid: uid_t) -> Result<Option<UserEntry>, String> {
+	with_buffer(|entry: &mut passwd, buf, result| unsafe {
+		libc::getpwuid_r(uid, entry, buf.as_mut_ptr(), buf.len(), result)
+	}).map(|entry| entry.map(|(pw, _buf)| UserEntry { name: unsafe { CStr::from_ptr(pw.pw_name) }.to_owned(), uid: pw.pw_uid, gid: pw.pw_gid }))
+}
+
+fn lookup_group(name: &str) -> Result<Option<gid_t>, String> {
+	let c_name = CString::new(name).map_err(|e| e.to_string())?;
+	with_buffer(|entry: &mut group, buf, result| unsafe {
+		libc::getgrnam_r(c_name.as_ptr(), entry, buf.as_mut_ptr(), buf.len(), result)
+	}).map(|entry| entry.map(|(gr, _)| gr.gr_gid))
+}
+
+// Calls a getpw*_r / getgr*_r style function, growing the string buffer on ERANGE.
+// The returned buffer must outlive any pointer read from the entry.
+fn with_buffer<T, F>(mut lookup: F) -> Result<Option<(T, Vec<c_char>)>, String>
+where
+	F: FnMut(&mut T, &mut Vec<c_char>, *mut *mut T) -> c_int,
+{
+	let mut buf: Vec<c_char> = vec![0; 1024];
+	loop {
+		let mut entry: T = unsafe { mem::zeroed() };
+		let mut result: *mut T = ptr::null_mut();
+		match lookup(&mut entry, &mut buf, &mut result) {
+			0 if result.is_null() => return Ok(None),
+			0 => return Ok(Some((entry, buf))),
+			ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+			errno => return Err(io::Error::from_raw_os_error(errno).to_string()),
+		}
+	}
+}
+
+fn group_list(name: &CStr, gid: gid_t) -> Result<Vec<gid_t>, String> {
+	let mut groups: Vec<gid_t> = vec![0; 32];
+	loop {
+		let mut count = groups.len() as c_int;
+		let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+		if ret >= 0 {
+			groups.truncate(count as usize);
+			return Ok(groups);
+		}
+		if count as usize <= groups.len() {
+			groups.resize(groups.len() * 2, 0);
+		} else {
+			groups.resize(count as usize, 0);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn quoted_ids() {
+		let name = |id: &str| Some(Account::Name(id.to_string()));
+		let credentials = Credentials::resolve(&name("54321"), &name("54322"), &None).unwrap().unwrap();
+		assert_eq!(credentials, Credentials { uid: Some(54321), gid: Some(54322), groups: vec![54322] });
+		assert!(Credentials::resolve(&name("no-such-user-here"), &None, &None).is_err());
+	}
+}
@@ -0,0 +1,193 @@
+use std::{collections::{BTreeMap, HashMap}, error::Error, fmt};
+use serde::{Serialize, Deserialize};
+use crate::{process::{Process, Status}, task_utils::Config};
+
+// When a dependency counts as met.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+	// Its processes have been spawned.
+	Started,
+	// Its processes are Running, i.e. past starttime or reported ready.
+	#[default]
+	#[serde(alias = "ready")]
+	Running,
+	// Its processes exited with one of its exitcodes, for one-shot jobs.
+	Completed,
+}
+
+impl fmt::Display for Condition {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Condition::Started => write!(f, "started"),
+			Condition::Running => write!(f, "running"),
+			Condition::Completed => write!(f, "completed"),
+		}
+	}
+}
+
+// `depends_on:` entry: a task name, or { task, condition }.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged, expecting = "expected a task name or { task, condition }")]
+pub enum Dependency {
+	Task(String),
+	Full {
+		task: String,
+		#[serde(default)]
+		condition: Condition,
+	},
+}
+
+impl Dependency {
+	pub fn task(&self) -> &str {
+		match self {
+			Dependency::Task(task) => task,
+			Dependency::Full { task, .. } => task,
+		}
+	}
+
+	pub fn condition(&self) -> Condition {
+		match self {
+			Dependency::Task(_) => Condition::default(),
+			Dependency::Full { condition, .. } => *condition,
+		}
+	}
+
+	// Whether every process of the task it names satisfies the condition.
+	pub fn is_met(&self, processes: &[Process], config: &Config) -> bool {
+		processes.iter().all(|p| match self.condition() {
+			Condition::Started => p.child.is_some() && !matches!(p.status, Status::Stopping | Status::Restarting),
+			Condition::Running => p.status == Status::Running,
+			Condition::Completed => p.status == Status::Stopped && p.exit_code.is_some_and(|code| config.exitcodes.contains(&code)),
+		})
+	}
+}
+
+impl fmt::Display for Dependency {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} ({})", self.task(), self.condition())
+	}
+}
+
+// Task names with every task after the ones it depends on, or the path of a
+// cycle ("a -> b -> a"). Otherwise lower priorities come first, then names.
+pub fn start_order<'a>(tasks: impl IntoIterator<Item = (&'a String, &'a Config)>) -> Result<Vec<String>, String> {
+	let tasks: BTreeMap<&str, &Config> = tasks.into_iter().map(|(name, config)| (name.as_str(), config)).collect();
+	let mut names: Vec<&str> = tasks.keys().copied().collect();
+	names.sort_by_key(|name| tasks[name].priority);
+	let mut order = vec![];
+	// false while a task is on the current path, true once it is placed.
+	let mut visited: HashMap<&str, bool> = HashMap::new();
+	for name in names {
+		visit(name, &tasks, &mut visited, &mut vec![], &mut order)?;
+	}
+	Ok(order)
+}
+
+fn visit<'a>(name: &'a str, tasks: &BTreeMap<&'a str, &'a Config>, visited: &mut HashMap<&'a str, bool>, path: &mut Vec<&'a str>, order: &mut Vec<String>) -> Result<(), String> {
+	match visited.get(name) {
+		Some(true) => return Ok(()),
+		Some(false) => {
+			let start = path.iter().position(|task| *task == name).unwrap_or(0);
+			let mut cycle = path[start..].to_vec();
+			cycle.push(name);
+			return Err(cycle.join(" -> "));
+		}
+		None => {}
+	}
+	visited.insert(name, false);
+	path.push(name);
+	// Unknown names are reported by validate, they are just skipped here.
+	let mut deps: Vec<&str> = tasks[name].depends_on.iter().map(|dep| dep.task()).filter(|dep| tasks.contains_key(dep)).collect();
+	deps.sort_by_key(|dep| tasks[dep].priority);
+	for dep in deps {
+		visit(dep, tasks, visited, path, order)?;
+	}
+	path.pop();
+	visited.insert(name, true);
+	order.push(name.to_string());
+	Ok(())
+}
+
+pub fn validate(configs: &BTreeMap<String, Config>) -> Result<(), Box<dyn Error>> {
+	for (name, config) in configs {
+		for dep in &config.depends_on {
+			if !configs.contains_key(dep.task()) {
+				return Err(format!("{}: depends_on: unknown task {}", name, dep.task()).into());
+			}
+		}
+	}
+	start_order(configs).map_err(|cycle| format!("depends_on: dependency cycle: {}", cycle))?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn configs(yaml: &str) -> BTreeMap<String, Config> {
+		serde_yaml::from_str(yaml).unwrap()
+	}
+
+	#[test]
+	fn dependencies_come_first() {
+		let configs = configs("
+api: { cmd: x, depends_on: [db, { task: migrate, condition: completed }] }
+worker: { cmd: x, depends_on: [{ task: api, condition: started }] }
+migrate: { cmd: x, depends_on: [db] }
+db: { cmd: x }
+");
+		assert_eq!(start_order(&configs), Ok(vec!["db".to_string(), "migrate".to_string(), "api".to_string(), "worker".to_string()]));
+	}
+
+	#[test]
+	fn priority_then_name_break_ties() {
+		let configs = configs("
+c: { cmd: x }
+b: { cmd: x, priority: 5 }
+a: { cmd: x }
+late: { cmd: x, priority: 2000 }
+early: { cmd: x, priority: 1, depends_on: [late] }
+");
+		// A dependency starts first whatever its priority.
+		assert_eq!(start_order(&configs), Ok(vec!["late", "early", "b", "a", "c"].into_iter().map(String::from).collect()));
+	}
+
+	#[test]
+	fn conditions() {
+		let configs = configs("
+a: { cmd: x, depends_on: [b, { task: c, condition: ready }, { task: d, condition: completed }, { task: e }] }
+b: { cmd: x }
+c: { cmd: x }
+d: { cmd: x }
+e: { cmd: x }
+");
+		let conditions: Vec<Condition> = configs["a"].depends_on.iter().map(|dep| dep.condition()).collect();
+		assert_eq!(conditions, vec![Condition::Running, Condition::Running, Condition::Completed, Condition::Running]);
+	}
+
+	#[test]
+	fn cycle_path() {
+		let configs = configs("
+a: { cmd: x, depends_on: [b] }
+b: { cmd: x, depends_on: [c] }
+c: { cmd: x, depends_on: [a] }
+d: { cmd: x }
+");
+		assert_eq!(start_order(&configs), Err("a -> b -> c -> a".to_string()));
+		let error = validate(&configs).unwrap_err().to_string();
+		assert_eq!(error, "depends_on: dependency cycle: a -> b -> c -> a");
+	}
+
+	#[test]
+	fn self_dependency_is_a_cycle() {
+		let configs = configs("a: { cmd: x, depends_on: [a] }");
+		assert_eq!(start_order(&configs), Err("a -> a".to_string()));
+	}
+
+	#[test]
+	fn unknown_task() {
+		let configs = configs("a: { cmd: x, depends_on: [nope] }");
+		assert_eq!(validate(&configs).unwrap_err().to_string(), "a: depends_on: unknown task nope");
+	}
+}
@@ -0,0 +1,66 @@
+use std::{io, os::unix::io::RawFd, sync::atomic::{AtomicI32, Ordering}, time::Instant};
+use libc::{c_int, pollfd, POLLIN, SIGCHLD, O_CLOEXEC, O_NONBLOCK, EINTR};
+
+// Write end of the self-pipe, reachable from signal handlers and other threads.
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+pub struct EventLoop {
+	read_fd: RawFd,
+}
+
+impl EventLoop {
+	pub fn new() -> io::Result<EventLoop> {
+		let mut fds: [c_int; 2] = [-1; 2];
+		if unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC | O_NONBLOCK) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		WAKE_FD.store(fds[1], Ordering::SeqCst);
+		unsafe { libc::signal(SIGCHLD, handle_sigchld_signal as *const () as libc::sighandler_t) };
+		Ok(EventLoop { read_fd: fds[0] })
+	}
+
+	// Blocks until something wakes the loop (signal, terminal command, data on one
+	// of `fds`) or the deadline passes. Returns the fds that are ready to be read.
+	pub fn wait(&self, fds: &[RawFd], deadline: Option<Instant>) -> Vec<RawFd> {
+		let mut poll_fds: Vec<pollfd> = vec![pollfd { fd: self.read_fd, events: POLLIN, revents: 0 }];
+		poll_fds.extend(fds.iter().map(|&fd| pollfd { fd, events: POLLIN, revents: 0 }));
+		let timeout: c_int = match deadline {
+			Some(deadline) => {
+				let remaining = deadline.saturating_duration_since(Instant::now());
+				// Round up so the deadline has really passed when we wake up.
+				(remaining.as_millis() + 1).min(c_int::MAX as u128) as c_int
+			}
+			None => -1,
+		};
+		let ret = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout) };
+		if ret < 0 {
+			let err = io::Error::last_os_error();
+			if err.raw_os_error() != Some(EINTR) {
+				eprintln!("poll failed: {}", err);
+			}
+			return vec![];
+		}
+		if poll_fds[0].revents != 0 {
+			self.drain();
+		}
+		poll_fds[1..].iter().filter(|p| p.revents != 0).map(|p| p.fd).collect()
+	}
+
+	fn drain(&self) {
+		let mut buf = [0u8; 64];
+		while unsafe { libc::read(self.read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+	}
+}
+
+// Async-signal-safe: only a write(2) on a non-blocking pipe.
+pub fn wake() {
+	let fd = WAKE_FD.load(Ordering::SeqCst);
+	if fd >= 0 {
+		let byte = 1u8;
+		unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+	}
+}
+
+extern "C" fn handle_sigchld_signal(_: c_int) {
+	wake();
+}
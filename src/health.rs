@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, fmt, fs, io::{Read, Write}, net::{SocketAddr, TcpStream, ToSocketAddrs}, os::unix::process::CommandExt, process::{Command, Stdio}, sync::mpsc::{self, Receiver}, thread, time::{Duration, Instant, SystemTime}};
+use serde::{Serialize, Deserialize};
+use crate::{child_setup::ChildSetup, event, task_utils::{Cmd, Instance}};
+
+// `healthcheck:` of a task: exactly one of exec, tcp, http or file, run every
+// `interval` seconds once the process is Running.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+	pub exec: Option<Cmd>,
+	pub tcp: Option<u16>,
+	pub http: Option<String>,
+	pub file: Option<String>,
+	// For file: how recently it must have been modified, in seconds.
+	#[serde(default = "default_max_age")]
+	pub max_age: u32,
+	#[serde(default = "default_interval")]
+	pub interval: u32,
+	#[serde(default = "default_timeout")]
+	pub timeout: u32,
+	// Consecutive failures before the process is Unhealthy.
+	#[serde(default = "default_failures")]
+	pub failures: u32,
+	#[serde(default)]
+	pub restart: bool,
+}
+
+fn default_max_age() -> u32 {
+	60
+}
+
+fn default_interval() -> u32 {
+	10
+}
+
+fn default_timeout() -> u32 {
+	3
+}
+
+fn default_failures() -> u32 {
+	3
+}
+
+impl HealthCheck {
+	pub fn probe(&self) -> Result<Probe, String> {
+		let mut probes = vec![];
+		if let Some(cmd) = &self.exec {
+			let argv = match cmd {
+				Cmd::Line(line) => shell_words::split(line).map_err(|e| format!("exec: {}", e))?,
+				Cmd::List(list) => list.clone(),
+			};
+			if argv.first().is_none_or(|program| program.is_empty()) {
+				return Err("exec: command is empty".to_string());
+			}
+			probes.push(Probe::Exec(Exec { argv, workingdir: None, env: BTreeMap::new(), setup: None }));
+		}
+		if let Some(port) = self.tcp {
+			probes.push(Probe::Tcp(port));
+		}
+		if let Some(url) = &self.http {
+			probes.push(parse_url(url).map_err(|e| format!("http: {}", e))?);
+		}
+		if let Some(path) = &self.file {
+			probes.push(Probe::File(path.clone(), Duration::from_secs(self.max_age as u64)));
+		}
+		if probes.len() != 1 {
+			return Err("expects exactly one of exec, tcp, http or file".to_string());
+		}
+		if self.interval == 0 || self.timeout == 0 || self.failures == 0 {
+			return Err("interval, timeout and failures must be at least 1".to_string());
+		}
+		Ok(probes.remove(0))
+	}
+}
+
+#[derive(Debug, Clone)]
+pub enum Probe {
+	Exec(Exec),
+	Tcp(u16),
+	Http { host: String, port: u16, path: String },
+	File(String, Duration),
+}
+
+impl fmt::Display for Probe {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Probe::Exec(exec) => write!(f, "exec {}", shell_words::join(&exec.argv)),
+			Probe::Tcp(port) => write!(f, "tcp port {}", port),
+			Probe::Http { host, port, path } => write!(f, "http://{}:{}{}", host, port, path),
+			Probe::File(path, max_age) => write!(f, "file {} modified within {}s", path, max_age.as_secs()),
+		}
+	}
+}
+
+// http://host[:port][/path]
+fn parse_url(url: &str) -> Result<Probe, String> {
+	let rest = url.strip_prefix("http://").ok_or(format!("'{}' is not an http:// URL", url))?;
+	let (authority, path) = match rest.find('/') {
+		Some(slash) => (&rest[..slash], &rest[slash..]),
+		None => (rest, "/"),
+	};
+	let (host, port) = match authority.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid port '{}'", port))?),
+		None => (authority, 80),
+	};
+	if host.is_empty() {
+		return Err(format!("'{}' has no host", url));
+	}
+	Ok(Probe::Http { host: host.to_string(), port, path: path.to_string() })
+}
+
+// An exec check runs like the process it checks: same user, umask, limits,
+// working directory and environment.
+#[derive(Debug, Clone)]
+pub struct Exec {
+	pub argv: Vec<String>,
+	pub workingdir: Option<String>,
+	pub env: BTreeMap<String, String>,
+	pub setup: Option<ChildSetup>,
+}
+
+impl Probe {
+	// The probe for one process of the task, see create_task_and_processes.
+	pub fn for_process(self, instance: &Instance, setup: ChildSetup) -> Probe {
+		match self {
+			Probe::Exec(exec) => Probe::Exec(Exec {
+				workingdir: Some(instance.workingdir.clone()),
+				env: instance.env.clone(),
+				setup: Some(setup),
+				..exec
+			}),
+			probe => probe,
+		}
+	}
+
+	// Blocking: runs on its own thread, see HealthState::launch.
+	fn run(&self, timeout: Duration) -> Result<(), String> {
+		match self {
+			Probe::Exec(exec) => run_exec(exec, timeout),
+			Probe::Tcp(port) => {
+				TcpStream::connect_timeout(&SocketAddr::from(([127, 0, 0, 1], *port)), timeout)
+					.map(|_| ())
+					.map_err(|e| format!("port {}: {}", port, e))
+			}
+			Probe::Http { host, port, path } => run_http(host, *port, path, timeout),
+			Probe::File(path, max_age) => {
+				let modified = fs::metadata(path).and_then(|m| m.modified()).map_err(|e| format!("{}: {}", path, e))?;
+				let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+				if age > *max_age {
+					return Err(format!("{} not modified for {}s", path, age.as_secs()));
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+fn run_exec(exec: &Exec, timeout: Duration) -> Result<(), String> {
+	let mut cmd = Command::new(&exec.argv[0]);
+	cmd.args(&exec.argv[1..]).envs(&exec.env).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+	if let Some(workingdir) = &exec.workingdir {
+		cmd.current_dir(workingdir);
+	}
+	if let Some(setup) = exec.setup.clone() {
+		unsafe { cmd.pre_exec(move || setup.apply()) };
+	}
+	let mut child = cmd.spawn().map_err(|e| format!("{}: {}", exec.argv[0], e))?;
+	let started = Instant::now();
+	loop {
+		match child.try_wait() {
+			Ok(Some(status)) if status.success() => return Ok(()),
+			Ok(Some(status)) => return Err(format!("check {}", status)),
+			Ok(None) if started.elapsed() > timeout => {
+				let _ = child.kill();
+				let _ = child.wait();
+				return Err(format!("check timed out after {}s", timeout.as_secs()));
+			}
+			Ok(None) => thread::sleep(Duration::from_millis(20)),
+			Err(e) => return Err(e.to_string()),
+		}
+	}
+}
+
+fn run_http(host: &str, port: u16, path: &str, timeout: Duration) -> Result<(), String> {
+	let addr = (host, port).to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+		.ok_or(format!("cannot resolve {}", host))?;
+	let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| format!("{}:{}: {}", host, port, e))?;
+	stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))).map_err(|e| e.to_string())?;
+	let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+	stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+	let mut response = [0u8; 64];
+	let n = stream.read(&mut response).map_err(|e| e.to_string())?;
+	// "HTTP/1.1 200 OK"
+	let status = String::from_utf8_lossy(&response[..n]).split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+	match status {
+		Some(code) if (200..400).contains(&code) => Ok(()),
+		Some(code) => Err(format!("HTTP {}", code)),
+		None => Err("invalid HTTP response".to_string()),
+	}
+}
+
+// Per process: when the next check is due, the one in flight, and the streak of failures.
+#[derive(Debug)]
+pub struct HealthState {
+	next_at: Instant,
+	in_flight: Option<Receiver<Result<(), String>>>,
+	pub failures: u32,
+	pub last_error: Option<String>,
+}
+
+impl HealthState {
+	// First check one interval after the process is Running.
+	pub fn new(interval: u32) -> HealthState {
+		HealthState { next_at: Instant::now() + Duration::from_secs(interval as u64), in_flight: None, failures: 0, last_error: None }
+	}
+
+	// The result of the check in flight, once it is done.
+	pub fn poll(&mut self) -> Option<Result<(), String>> {
+		let result = self.in_flight.as_ref()?.try_recv();
+		match result {
+			Ok(result) => {
+				self.in_flight = None;
+				Some(result)
+			}
+			Err(mpsc::TryRecvError::Empty) => None,
+			Err(mpsc::TryRecvError::Disconnected) => {
+				self.in_flight = None;
+				Some(Err("check thread died".to_string()))
+			}
+		}
+	}
+
+	// Starts the next check if it is due and none is running.
+	pub fn launch(&mut self, probe: Probe, check: &HealthCheck) {
+		if self.in_flight.is_some() || Instant::now() < self.next_at {
+			return;
+		}
+		self.next_at = Instant::now() + Duration::from_secs(check.interval as u64);
+		let timeout = Duration::from_secs(check.timeout as u64);
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || {
+			let _ = sender.send(probe.run(timeout));
+			event::wake();
+		});
+		self.in_flight = Some(receiver);
+	}
+
+	// While a check runs its thread wakes the loop, so there is nothing to wait for.
+	pub fn deadline(&self) -> Option<Instant> {
+		match self.in_flight {
+			Some(_) => None,
+			None => Some(self.next_at),
+		}
+	}
+}
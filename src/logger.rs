@@ -0,0 +1,226 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::PathBuf, thread::{self, JoinHandle}, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Serialize, Deserialize};
+use flate2::{write::GzEncoder, Compression};
+use crate::{output::Stream, task_utils::parse_size};
+
+// `stdout: out.log`, or `stdout: { path: out.log, maxbytes: 10M, backups: 5, compress: true }`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(from = "RawLogConfig")]
+pub struct LogConfig {
+	pub path: String,
+	// 0 disables rotation.
+	pub maxbytes: u64,
+	pub backups: u32,
+	pub compress: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "expected a path or { path, maxbytes, backups, compress }")]
+enum RawLogConfig {
+	Path(String),
+	Full {
+		path: String,
+		#[serde(default, deserialize_with = "size_deserializer")]
+		maxbytes: u64,
+		#[serde(default)]
+		backups: u32,
+		#[serde(default)]
+		compress: bool,
+	},
+}
+
+impl From<RawLogConfig> for LogConfig {
+	fn from(raw: RawLogConfig) -> LogConfig {
+		match raw {
+			RawLogConfig::Path(path) => LogConfig { path, maxbytes: 0, backups: 0, compress: false },
+			RawLogConfig::Full { path, maxbytes, backups, compress } => LogConfig { path, maxbytes, backups, compress },
+		}
+	}
+}
+
+fn size_deserializer<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum RawSize {
+		Bytes(u64),
+		Text(String),
+	}
+	match RawSize::deserialize(deserializer)? {
+		RawSize::Bytes(bytes) => Ok(bytes),
+		RawSize::Text(text) => parse_size(&text).map_err(serde::de::Error::custom),
+	}
+}
+
+impl std::fmt::Display for LogConfig {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}", self.path)?;
+		if self.maxbytes > 0 {
+			write!(f, " (rotate at {} bytes, keep {}{})", self.maxbytes, self.backups, if self.compress { ", gzip" } else { "" })?;
+		}
+		Ok(())
+	}
+}
+
+// How captured lines are written: as is, prefixed with their origin, or as JSON lines.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	#[default]
+	Raw,
+	Prefixed,
+	Json,
+}
+
+impl std::fmt::Display for LogFormat {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			LogFormat::Raw => write!(f, "raw"),
+			LogFormat::Prefixed => write!(f, "prefixed"),
+			LogFormat::Json => write!(f, "json"),
+		}
+	}
+}
+
+// Where a captured line comes from.
+pub struct Origin<'a> {
+	pub task: &'a str,
+	pub id: u32,
+	pub pid: u32,
+	pub stream: Stream,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+	time: &'a str,
+	task: &'a str,
+	id: u32,
+	pid: u32,
+	stream: String,
+	line: &'a str,
+}
+
+impl LogFormat {
+	// One formatted record, newline included, for a line read without its newline.
+	pub fn format(&self, origin: &Origin, line: &[u8]) -> Vec<u8> {
+		let text = String::from_utf8_lossy(line);
+		let mut record = match self {
+			LogFormat::Raw => line.to_vec(),
+			LogFormat::Prefixed => format!("{} {}:{} [{}] {}: {}", timestamp(), origin.task, origin.id, origin.pid, origin.stream, text).into_bytes(),
+			LogFormat::Json => {
+				let time = timestamp();
+				let json = JsonLine { time: &time, task: origin.task, id: origin.id, pid: origin.pid, stream: origin.stream.to_string(), line: &text };
+				serde_json::to_vec(&json).unwrap_or_default()
+			}
+		};
+		record.push(b'\n');
+		record
+	}
+}
+
+// Local time in RFC 3339 with milliseconds, e.g. 2024-05-01T14:03:12.345+02:00.
+fn timestamp() -> String {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	let secs = now.as_secs() as libc::time_t;
+	let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+	if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+		return String::new();
+	}
+	let offset = tm.tm_gmtoff / 60;
+	let zone = match offset {
+		0 => "Z".to_string(),
+		_ => format!("{}{:02}:{:02}", if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60),
+	};
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}",
+		tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec, now.subsec_millis(), zone)
+}
+
+#[derive(Debug)]
+pub struct LogSink {
+	config: LogConfig,
+	file: File,
+	size: u64,
+	// gzip of the last rotated file, on its own thread so a large log does not stall the loop.
+	compressing: Option<JoinHandle<()>>,
+}
+
+impl LogSink {
+	pub fn open(config: &LogConfig) -> io::Result<LogSink> {
+		let file = Self::open_file(&config.path)?;
+		let size = file.metadata()?.len();
+		Ok(LogSink { config: config.clone(), file, size, compressing: None })
+	}
+
+	fn open_file(path: &str) -> io::Result<File> {
+		OpenOptions::new().create(true).append(true).open(path)
+	}
+
+	pub fn path(&self) -> &str {
+		&self.config.path
+	}
+
+	pub fn write(&mut self, data: &[u8]) {
+		if self.config.maxbytes > 0 && self.size > 0 && self.size + data.len() as u64 > self.config.maxbytes {
+			if let Err(e) = self.rotate() {
+				eprintln!("{}: rotation failed: {}", self.config.path, e);
+			}
+		}
+		match self.file.write_all(data) {
+			Ok(()) => self.size += data.len() as u64,
+			Err(e) => eprintln!("{}: {}", self.config.path, e),
+		}
+	}
+
+	// Picks up a file that was moved away by an external tool (SIGUSR2).
+	pub fn reopen(&mut self) -> io::Result<()> {
+		self.file = Self::open_file(&self.config.path)?;
+		self.size = self.file.metadata()?.len();
+		Ok(())
+	}
+
+	// out.log -> out.log.1 -> out.log.2 ... keeping `backups` files.
+	fn rotate(&mut self) -> io::Result<()> {
+		if self.config.backups == 0 {
+			self.file = OpenOptions::new().write(true).truncate(true).open(&self.config.path)?;
+			self.size = 0;
+			return Ok(());
+		}
+		// The previous file must be compressed before the backups shift again.
+		if let Some(previous) = self.compressing.take() {
+			let _ = previous.join();
+		}
+		let suffix = if self.config.compress { ".gz" } else { "" };
+		let backup = |n: u32| PathBuf::from(format!("{}.{}{}", self.config.path, n, suffix));
+		let _ = fs::remove_file(backup(self.config.backups));
+		for n in (1..self.config.backups).rev() {
+			if backup(n).exists() {
+				fs::rename(backup(n), backup(n + 1))?;
+			}
+		}
+		let first = format!("{}.1", self.config.path);
+		fs::rename(&self.config.path, &first)?;
+		if self.config.compress {
+			self.compressing = Some(thread::spawn(move || {
+				if let Err(e) = compress(&first) {
+					eprintln!("{}: compression failed: {}", first, e);
+				}
+			}));
+		}
+		self.reopen()
+	}
+}
+
+// Written under a temporary name, so an exit halfway leaves no truncated .gz behind.
+fn compress(path: &str) -> io::Result<()> {
+	let gz = format!("{}.gz", path);
+	let partial = format!("{}.part", gz);
+	let mut input = File::open(path)?;
+	let output = File::create(&partial)?;
+	let mut encoder = GzEncoder::new(output, Compression::default());
+	io::copy(&mut input, &mut encoder)?;
+	encoder.finish()?;
+	fs::rename(&partial, &gz)?;
+	fs::remove_file(path)
+}
@@ -3,15 +3,33 @@ mod terminal;
 mod process;
 mod task;
 mod monitor;
+mod event;
+mod credentials;
+mod child_setup;
+mod rlimits;
+mod cgroup;
+mod logger;
+mod output;
+mod template;
+mod pty;
+mod health;
+mod notify;
+mod patterns;
+mod dependency;
 
+use child_setup::ChildSetup;
+use rlimits::Rlimit;
+use cgroup::Cgroup;
+use logger::{LogConfig, LogFormat, LogSink};
+use template::Vars;
 use process::Process;
 use task::Task;
-use task_utils::{print_config};
-use task_utils::Config;
-use std::collections::{HashMap, BTreeMap, VecDeque};
+use task_utils::{Config, ConfigFile, Readiness, Stdin};
+use notify::NotifySocket;
+use patterns::Patterns;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{fs::File, process::exit};
 use std::io::{Read, self};
 use std::{env};
@@ -20,6 +38,7 @@ use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
 use std::process::{Command, Stdio};
 
+use crate::event::EventLoop;
 use crate::monitor::Monitor;
 use crate::terminal::{TermInput, Terminal};
 
@@ -30,76 +49,137 @@ macro_rules! print_exit {
 	};
 }
 
-pub fn parse_config_file(path: &PathBuf) -> Result<BTreeMap<String, Config>, Box<dyn Error>> {
+pub fn parse_config_file(path: &PathBuf) -> Result<ConfigFile, Box<dyn Error>> {
 	let mut file = File::open(path)?;
 	let mut content = String::new();
 	file.read_to_string(&mut content)?;
-	let configs: BTreeMap<String, Config> = serde_yaml::from_str(&content)?;
-	Ok(configs)
+	let config: ConfigFile = serde_yaml::from_str(&content)?;
+	if config.taskmaster.max_concurrent_starts == Some(0) {
+		return Err("taskmaster: max_concurrent_starts: must be at least 1".into());
+	}
+	let here = template::config_dir(path);
+	for (name, task) in &config.tasks {
+		task.validate(name, &here)?;
+	}
+	task_utils::validate_log_paths(&config.tasks, &here)?;
+	dependency::validate(&config.tasks)?;
+	Ok(config)
 }
 
-//pabo
-fn set_cmd_output(cmd: &mut Command, path: &Option<String>, stdout: bool) -> Result<(), io::Error> {
-	if let Some(path) = path {
-		match OpenOptions::new().create(true).append(true).write(true).open(path) {
-			Ok(file) => {
-				if stdout {
-					cmd.stdout(file);
-				} else {
-					cmd.stderr(file);
-				}
-				Ok(())
-			}
-			Err(e) => {
-				if stdout {
-					cmd.stdout(Stdio::null());
-				} else {
-					cmd.stderr(Stdio::null());
-				}
-				Err(e)
-			}
-		}
-	} else {
-		if stdout {
-			cmd.stdout(Stdio::null());
-		} else {
-			cmd.stderr(Stdio::null());
-		}
-		Ok(())
+// Instances whose templates expand to the same file share one sink.
+fn open_sink(sinks: &mut Vec<LogSink>, log: &Option<LogConfig>) -> Result<Option<usize>, String> {
+	let Some(log) = log else { return Ok(None) };
+	if let Some(index) = sinks.iter().position(|sink| sink.path() == log.path) {
+		return Ok(Some(index));
 	}
+	let sink = LogSink::open(log).map_err(|e| format!("{}: {}", log.path, e))?;
+	sinks.push(sink);
+	Ok(Some(sinks.len() - 1))
 }
 
-fn create_task_and_processes(name: String, config: Config) -> (String, Task) {
+fn create_task_and_processes(name: String, config: Config, here: &Path) -> (String, Task) {
 	let mut task = Task::new(config, name.clone());
-	let cmd_split: VecDeque<&str> = task.config.cmd.split_whitespace().collect();
-	
+	// Autostart goes through the monitor's rollout, once dependencies are met.
+	if task.config.autostart {
+		task.queued = (0..task.config.numprocs).collect();
+		if !task.config.depends_on.is_empty() {
+			task.waiting = Some(task.config.depends_on.iter().map(|dep| dep.to_string()).collect::<Vec<_>>().join(", "));
+		}
+	}
+	let credentials = task.config.credentials();
+	let cgroup_settings = cgroup::settings(&task.config).unwrap_or_default();
+	let mut cgroup_error: Option<String> = None;
+	if cgroup::is_enabled(&task.config) {
+		match Cgroup::for_task(&name) {
+			Ok(cgroup) if !task.config.cgroup_per_process => {
+				cgroup_error = cgroup.apply(&cgroup_settings).err();
+				task.cgroup = Some(cgroup);
+			}
+			Ok(cgroup) => task.cgroup = Some(cgroup),
+			Err(e) => cgroup_error = Some(e),
+		}
+	}
+
 	for id in 0..task.config.numprocs {
-		let mut error: Option<Box<dyn Error>> = None;
-		let mut cmd_splited = cmd_split.clone();
-		let mut cmd = match cmd_splited.pop_front() {
-			Some(cmd_str) => Command::new(cmd_str),
-			None => {
-				error = Some(Box::new(io::Error::new(io::ErrorKind::Other, "Command is empty")));
+		let mut error: Option<Box<dyn Error>> = cgroup_error.clone().map(|e| Box::new(io::Error::other(e)) as Box<dyn Error>);
+		// With cgroup_per_process the limits apply to each instance instead of the whole task.
+		let process_cgroup = match &task.cgroup {
+			Some(task_cgroup) if task.config.cgroup_per_process => {
+				match task_cgroup.child(&id.to_string()).and_then(|c| c.apply(&cgroup_settings).map(|_| c)) {
+					Ok(cgroup) => Some(cgroup),
+					Err(e) => {
+						error = Some(Box::new(io::Error::other(e)));
+						None
+					}
+				}
+			}
+			_ => None,
+		};
+		let instance = task.config.instance(&Vars { task_name: &name, process_num: id, here });
+		let mut cmd = match &instance {
+			Ok(instance) => {
+				let mut cmd = Command::new(&instance.argv[0]);
+				cmd.args(&instance.argv[1..]);
+				cmd.envs(&instance.env);
+				cmd.current_dir(instance.workingdir.as_str());
+				cmd
+			}
+			Err(e) => {
+				error = Some(Box::new(io::Error::other(e.clone())));
 				Command::new("")
 			}
 		};
-		if let Some(env) = &task.config.env {
-			cmd.envs(env);
-		}
-		cmd.args(cmd_splited.clone());
-		cmd.current_dir(task.config.workingdir.as_str());
 
-		if let Err(e) = set_cmd_output(&mut cmd, &task.config.stdout, true) {
-			error = Some(Box::new(e));
+		let setup = ChildSetup {
+			umask: task.config.umask,
+			nice: task.config.nice,
+			rlimits: task.config.rlimits.iter().map(|(resource, value)| Rlimit::new(*resource, value)).collect(),
+			credentials: credentials.clone().unwrap_or_default(),
+			cgroup: process_cgroup.as_ref().or(task.cgroup.as_ref()).map(|c| c.procs_path()),
+			controlling_tty: task.config.stdin == Stdin::Pty,
+		};
+		// The health check gets the same credentials and limits, but stays out of the cgroup.
+		let probe = match (&instance, task.config.healthcheck.as_ref().map(|check| check.probe())) {
+			(Ok(instance), Some(Ok(probe))) => Some(probe.for_process(instance, ChildSetup { cgroup: None, controlling_tty: false, ..setup.clone() })),
+			_ => None,
+		};
+		let (stdout, stderr, stdin) = instance.map(|i| (i.stdout, i.stderr, i.stdin)).unwrap_or_default();
+		let mut open = |log| open_sink(&mut task.sinks, &log).unwrap_or_else(|e| {
+			error = Some(Box::new(io::Error::other(e)));
+			None
+		});
+		let (stdout_sink, stderr_sink) = (open(stdout), open(stderr));
+		// Output is captured even without a log file, for `tail`.
+		cmd.stdout(Stdio::piped());
+		cmd.stderr(Stdio::piped());
+		let mut notify = None;
+		if task.config.readiness == Readiness::Notify {
+			match NotifySocket::bind(&format!("{}-{}", name, id), credentials.as_ref().ok().and_then(|c| c.as_ref())) {
+				Ok(socket) => {
+					cmd.env("NOTIFY_SOCKET", &socket.path);
+					if let Some(watchdog) = task.config.watchdog {
+						cmd.env("WATCHDOG_USEC", (watchdog as u64 * 1_000_000).to_string());
+					}
+					notify = Some(socket);
+				}
+				Err(e) => error = Some(Box::new(io::Error::other(format!("notify socket: {}", e)))),
+			}
 		}
-		if let Err(e) = set_cmd_output(&mut cmd, &task.config.stdout, false) {
-			error = Some(Box::new(e));
+		let mut process = Process::new(id, name.clone(), cmd, task.config.stop_steps(), setup, task.config.stopasgroup, task.config.killasgroup);
+		process.cgroup = process_cgroup;
+		// The other formats label each line with its stream, so there redirect_stderr
+		// keeps the pipes apart and only sends stderr to the stdout sink.
+		process.merge_streams = task.config.redirect_stderr && task.config.log_format == LogFormat::Raw;
+		process.sinks = [stdout_sink, if task.config.redirect_stderr { stdout_sink } else { stderr_sink }];
+		process.log_format = task.config.log_format;
+		process.notify = notify;
+		process.probe = probe;
+		process.patterns = Patterns::compile(&task.config.ready_pattern, &task.config.alert_patterns).ok().flatten();
+		process.stdin_mode = stdin;
+		if let Err(e) = &credentials {
+			error = Some(Box::new(io::Error::other(e.clone())));
 		}
-		let mut process = Process::new(id, name.clone(), cmd, task.config.umask, task.config.stopsignal);
 		process.error = error;
-		if task.config.autostart {
-			process.start();
-		}
 		task.processes.push(process);
 	}
 	(name, task)
@@ -128,7 +208,7 @@ fn main() {
 	{
 		print_exit!("Wrong file extention. Expecting a YAML file.", 1);
 	}
-	let config = match parse_config_file(&path) {
+	let mut config = match parse_config_file(&path) {
 		Ok(cfg) => cfg,
 		Err(e) => { print_exit!(e, 1); }
 	};
@@ -147,14 +227,20 @@ fn main() {
 	// let mut content = String::new();
 	// file.read_to_string(&mut content)
 	// 	.expect("Could not read file...");
+	let events = match EventLoop::new() {
+		Ok(events) => events,
+		Err(e) => { print_exit!(e, 1); }
+	};
     let (sender, receiver): (Sender<TermInput>, Receiver<TermInput>) = mpsc::channel();
 	let mut tasks: HashMap<String, Task> = HashMap::new();
-	for (name, config) in config {
-		let (name, task) = create_task_and_processes(name, config);
+	let order = dependency::start_order(&config.tasks).unwrap_or_default();
+	for name in order {
+		let Some(config) = config.tasks.remove(&name) else { continue };
+		let (name, task) = create_task_and_processes(name, config, &template::config_dir(&path));
 		tasks.insert(name, task);
 	}
 
-    let mut monitor = Monitor::new(tasks, receiver, path);
+    let mut monitor = Monitor::new(tasks, config.taskmaster, receiver, path, events);
     let _th = thread::spawn(move || {
 		let mut terminal: Terminal = Terminal::new(sender);
 		terminal.read_input();
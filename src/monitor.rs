@@ -1,70 +1,130 @@
-use std::{collections::{HashMap, BTreeMap}, sync::{mpsc::Receiver, Arc, atomic::{AtomicBool, Ordering}}, process::{exit}, fs::{OpenOptions, File}, error::Error, io::Read, path::PathBuf};
-use crate::{process::{Status}, task::{Task}, terminal::{TermInput, ProcessArg}, task_utils::Config, parse_config_file, create_task_and_processes};
-use libc::{SIGHUP, signal};
+use std::{collections::{HashMap, BTreeMap}, sync::{mpsc::Receiver, atomic::{AtomicBool, Ordering}}, process::{exit}, error::Error, path::PathBuf, time::Instant, os::unix::io::RawFd};
+use crate::{cgroup, dependency, event::{self, EventLoop}, notify, process::{Process, Status}, task::{Task, PendingUpdate}, terminal::{TermInput, ProcessArg}, task_utils::{Config, Settings, print_config}, template::config_dir, output::TailFilter, parse_config_file, create_task_and_processes};
+use libc::{SIGHUP, SIGUSR2, signal};
 
 pub static RELOAD: AtomicBool = AtomicBool::new(false);
+pub static REOPEN_LOGS: AtomicBool = AtomicBool::new(false);
 
 
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CommandName {
 	START,
 	STOP,
     RESTART,
 	UPDATE,
     STATUS,
+	INFO,
 	SHUTDOWN,
 	KILL,
+	TAIL,
+	UNFOLLOW,
+	ATTACH,
+	INPUT,
+	DETACH,
 }
 
 pub struct Monitor {
 	tasks: HashMap<String, Task>,
 	receiver: Receiver<TermInput>,
 	config_path: PathBuf,
+	settings: Settings,
 	shutdown: bool,
+	events: EventLoop,
 }
 
 impl Monitor {
-	pub fn new(tasks: HashMap<String, Task>, receiver: Receiver<TermInput>, config_path: PathBuf) -> Monitor {
-		unsafe { signal(SIGHUP, Self::handle_sighup_signal as usize)};
-		let mut monitor = Monitor { tasks, receiver, config_path, shutdown: false };
+	pub fn new(tasks: HashMap<String, Task>, settings: Settings, receiver: Receiver<TermInput>, config_path: PathBuf, events: EventLoop) -> Monitor {
+		unsafe { signal(SIGHUP, Self::handle_sighup_signal as *const () as libc::sighandler_t)};
+		unsafe { signal(SIGUSR2, Self::handle_sigusr2_signal as *const () as libc::sighandler_t)};
+		let mut monitor = Monitor { tasks, receiver, config_path, settings, shutdown: false, events };
 		monitor.print_status(vec![]);
-		return monitor;
+		monitor
 	}
 
-	fn handle_sighup_signal(_: i32) {
+	extern "C" fn handle_sighup_signal(_: i32) {
 		RELOAD.store(true, Ordering::SeqCst);
+		event::wake();
+	}
+
+	extern "C" fn handle_sigusr2_signal(_: i32) {
+		REOPEN_LOGS.store(true, Ordering::SeqCst);
+		event::wake();
 	}
 
 	pub fn task_manager_loop(&mut self) {
 		loop {
-			for (_name, task) in self.tasks.iter_mut() {
+			for task in self.tasks.values_mut() {
 				task.try_wait();
 			}
+			self.apply_pending_updates();
+			if self.shutdown {
+				self.stop_in_reverse_order();
+			} else {
+				self.roll_out();
+			}
 			if self.shutdown && !self.process_still_alive() {
-				exit(0);
+				self.exit_cleanly();
 			}
-			self.receive_terminal_command();
-			if RELOAD.load(Ordering::SeqCst) == true {
+			while self.receive_terminal_command() {}
+			// A shutdown with nothing left to stop: exit now, nothing would wake the wait.
+			if self.shutdown && !self.process_still_alive() {
+				continue;
+			}
+			if RELOAD.load(Ordering::SeqCst) {
 				RELOAD.store(false, Ordering::SeqCst);
 				match self.update() {
 					Ok(()) => {},
 					Err(e) => { eprintln!("{:?}", e) }
 				}
 			}
+			if REOPEN_LOGS.swap(false, Ordering::SeqCst) {
+				for task in self.tasks.values_mut() {
+					task.reopen_logs();
+				}
+			}
+			let fds: Vec<RawFd> = self.tasks.values().flat_map(|task| task.watched_fds()).collect();
+			let ready = self.events.wait(&fds, self.next_deadline());
+			for task in self.tasks.values_mut() {
+				task.read_outputs(&ready);
+			}
 		}
 	}
 
-	fn receive_terminal_command(&mut self) {
+	// Removes the cgroups and notify sockets the supervisor created, then exits.
+	fn exit_cleanly(&self) -> ! {
+		for task in self.tasks.values() {
+			task.remove_cgroups();
+		}
+		cgroup::remove_tasks_root();
+		notify::cleanup();
+		exit(0);
+	}
+
+	// Earliest instant at which a process state transition is due, if any.
+	fn next_deadline(&self) -> Option<Instant> {
+		self.tasks.values().filter_map(|task| task.next_deadline()).min()
+	}
+
+	fn receive_terminal_command(&mut self) -> bool {
 		match self.receiver.try_recv() {
 			Ok(msg) => {
 				let cmd: CommandName = msg.cmd_name;
 				let args: Vec<ProcessArg> = msg.args;
+				let tail = msg.tail.unwrap_or_default();
+				let input = msg.input;
+				let reply = msg.reply;
 				match cmd {
 					CommandName::START => {
 						for arg in args {
-							if let Some(task) = self.tasks.get_mut(arg.name.as_str()) {
+							let unmet = self.tasks.get(arg.name.as_str()).map(|task| self.unmet_dependencies(task));
+							if let (Some(task), Some(unmet)) = (self.tasks.get_mut(arg.name.as_str()), unmet) {
 								// println!("arg:{:?}", arg);
-								task.start(arg.id);
+								if unmet.is_empty() {
+									task.start(arg.id);
+								} else {
+									task.start_when_met(arg.id, unmet.join(", "));
+								}
 							} else {
 								eprintln!("Task {} not found", arg.name);
 							}
@@ -91,6 +151,76 @@ impl Monitor {
 					CommandName::STATUS => {
 						self.print_status(args);
 					}
+					CommandName::INFO => {
+						for arg in args {
+							if let Some(task) = self.tasks.get(arg.name.as_str()) {
+								print_config(&task.name, &task.config);
+							} else {
+								eprintln!("Task {} not found", arg.name);
+							}
+						}
+					}
+					CommandName::TAIL => {
+						// Label lines as soon as more than one process is shown.
+						let prefix = args.len() > 1 || args.iter().any(|arg| {
+							arg.id == "*" && self.tasks.get(arg.name.as_str()).is_some_and(|task| task.processes.len() > 1)
+						});
+						let filter = TailFilter { stream: tail.stream, prefix };
+						let mut following = false;
+						for arg in args {
+							if let Some(task) = self.tasks.get_mut(arg.name.as_str()) {
+								task.tail(arg.id.clone(), filter, tail.lines);
+								if tail.follow {
+									following |= task.follow(arg.id, filter);
+								}
+							} else {
+								eprintln!("Task {} not found", arg.name);
+							}
+						}
+						if following {
+							println!("--- following, press any key to stop ---");
+						}
+					}
+					CommandName::UNFOLLOW => {
+						for task in self.tasks.values_mut() {
+							task.unfollow();
+						}
+					}
+					CommandName::ATTACH => {
+						let attached = match args.first().map(|arg| self.find_process(arg)) {
+							Some(Ok(process)) if process.stdin.is_some() => {
+								println!("--- attached to {}, press Ctrl-] to detach ---", process.name());
+								process.attach();
+								true
+							}
+							Some(Ok(process)) => {
+								eprintln!("{} has no stdin to attach to (set stdin: pipe or pty)", process.name());
+								false
+							}
+							Some(Err(e)) => { eprintln!("{}", e); false }
+							None => false,
+						};
+						if let Some(reply) = reply {
+							let _ = reply.send(attached);
+						}
+					}
+					CommandName::INPUT => {
+						match args.first().map(|arg| self.find_process(arg)) {
+							Some(Ok(process)) => {
+								if let Err(e) = process.write_stdin(&input) {
+									eprintln!("{}: {}", process.name(), e);
+								}
+							}
+							Some(Err(e)) => eprintln!("{}", e),
+							None => {}
+						}
+					}
+					CommandName::DETACH => {
+						if let Some(Ok(process)) = args.first().map(|arg| self.find_process(arg)) {
+							process.attached = false;
+						}
+						println!("\n--- detached ---");
+					}
 					CommandName::UPDATE => {
 						match self.update() {
 							Ok(()) => {},
@@ -100,20 +230,91 @@ impl Monitor {
 					CommandName::SHUTDOWN => {
 						println!("Shutting down . . .");
 						self.shutdown = true;
-						for (_, task) in &mut self.tasks {
-							task.stop("*".to_string());
+						for task in self.tasks.values_mut() {
+							task.pending = None;
+							task.queued.clear();
+							task.waiting = None;
 						}
+						self.stop_in_reverse_order();
 					}
 					CommandName::KILL => {
 						println!("Shutting down murdering all childs :( . . .");
-						for (_, task) in &mut self.tasks {
+						for task in self.tasks.values_mut() {
 							task.kill();
 						}
-						exit(0);
+						self.exit_cleanly();
 					}
 				}
+				true
+			}
+			Err(_) => false,
+		}
+	}
+
+	// A single process: `task:id`, or just `task` when it has only one.
+	fn find_process(&mut self, arg: &ProcessArg) -> Result<&mut Process, String> {
+		let task = self.tasks.get_mut(arg.name.as_str()).ok_or(format!("Task {} not found", arg.name))?;
+		if arg.id == "*" && task.processes.len() > 1 {
+			return Err(format!("Task {} has several processes, pick one with {}:id", arg.name, arg.name));
+		}
+		task.processes.iter_mut()
+			.find(|p| arg.id == "*" || p.id.to_string() == arg.id)
+			.ok_or(format!("Process {}:{} not found", arg.name, arg.id))
+	}
+
+	fn start_order(&self) -> Vec<String> {
+		dependency::start_order(self.tasks.iter().map(|(name, task)| (name, &task.config))).unwrap_or_default()
+	}
+
+	fn unmet_dependencies(&self, task: &Task) -> Vec<String> {
+		task.config.depends_on.iter()
+			.filter(|dep| !self.tasks.get(dep.task()).is_some_and(|t| dep.is_met(&t.processes, &t.config)))
+			.map(|dep| dep.to_string())
+			.collect()
+	}
+
+	// Autostarts queued processes in start order: a task goes once its dependencies
+	// are met, its instances start_stagger apart, with at most max_concurrent_starts
+	// processes Starting at a time. A chain of `started` conditions comes up in one pass.
+	fn roll_out(&mut self) {
+		let mut starting = self.tasks.values().flat_map(|task| &task.processes).filter(|p| p.status == Status::Starting).count() as u32;
+		for name in self.start_order() {
+			let Some(task) = self.tasks.get(&name).filter(|task| !task.queued.is_empty() && task.pending.is_none()) else { continue };
+			let unmet = self.unmet_dependencies(task);
+			let Some(task) = self.tasks.get_mut(&name) else { continue };
+			if !unmet.is_empty() {
+				task.waiting = Some(unmet.join(", "));
+				continue;
+			}
+			if task.waiting.take().is_some() {
+				println!("Task {}: dependencies met, starting", name);
+			}
+			while self.settings.max_concurrent_starts.is_none_or(|max| starting < max) && !task.queued.is_empty() {
+				match task.start_queued() {
+					Some(true) => starting += 1,
+					// Failed to spawn: takes no slot, go on with the next instance.
+					Some(false) => {}
+					None => break,
+				}
+			}
+		}
+	}
+
+	// Stops tasks once every task depending on them, or started after them with a
+	// higher priority, is down.
+	fn stop_in_reverse_order(&mut self) {
+		let order = self.start_order();
+		for (position, name) in order.iter().enumerate().rev() {
+			let Some(priority) = self.tasks.get(name).map(|task| task.config.priority) else { continue };
+			let before_down = order[position + 1..].iter()
+				.filter_map(|later| self.tasks.get(later))
+				.filter(|task| task.config.priority > priority || task.config.depends_on.iter().any(|dep| dep.task() == name))
+				.all(|task| task.is_drained());
+			let Some(task) = self.tasks.get_mut(name) else { continue };
+			let active = task.processes.iter().any(|p| p.child.is_some() || p.retry_at.is_some());
+			if before_down && active {
+				task.stop("*".to_string());
 			}
-			Err(_) => {},
 		}
 	}
 
@@ -125,40 +326,60 @@ impl Monitor {
 		})
 	}
 
+	// Changed and removed tasks are only marked here; they are swapped out by
+	// apply_pending_updates once their processes are down, so the loop keeps running.
 	fn update(&mut self) -> Result<(), Box<dyn Error>> {
-		let mut to_remove: Vec<String> = vec![];
-		let mut configs: BTreeMap<String, Config> = parse_config_file(&self.config_path)?;
+		let file = parse_config_file(&self.config_path)?;
+		self.settings = file.taskmaster;
+		let mut configs: BTreeMap<String, Config> = file.tasks;
 		for (name, task) in &mut self.tasks {
 			if let Some(config) = configs.remove(name) {
-				if task.config != config {
-					task.stop("*".to_string());
-					task.wait_procs_to_stop();
-					*task = create_task_and_processes(name.clone(), config).1;
+				if task.config != config || task.pending.is_some() {
+					task.schedule_update(PendingUpdate::Replace(Box::new(config)));
 				}
 			} else {
-				//STOP DELETE TASK
-				task.stop("*".to_string());
-				task.wait_procs_to_stop();
-				to_remove.push(name.clone());
+				task.schedule_update(PendingUpdate::Remove);
 			}
 		}
-		for name in to_remove {
-			self.tasks.remove(name.as_str());
-		}
 		//START HANDLE NEW TASKS
 		for (name, config) in configs {
-			let (name, new_task) = create_task_and_processes(name, config);
+			let (name, new_task) = create_task_and_processes(name, config, &config_dir(&self.config_path));
 			self.tasks.insert(name, new_task);
 		}
-		println!("Update complete");
+		println!("Configuration reloaded");
+		self.apply_pending_updates();
 		Ok(())
 	}
 
+	fn apply_pending_updates(&mut self) {
+		let ready: Vec<String> = self.tasks.iter()
+			.filter(|(_, task)| task.pending.is_some() && task.is_drained())
+			.map(|(name, _)| name.clone())
+			.collect();
+		for name in ready {
+			if let Some(mut task) = self.tasks.remove(&name) {
+				task.remove_cgroups();
+				let pending = task.pending.take();
+				// Dropped before the replacement is built: its notify sockets live at the
+				// same paths and unlink them when dropped.
+				drop(task);
+				match pending {
+					Some(PendingUpdate::Replace(config)) => {
+						let (name, new_task) = create_task_and_processes(name, *config, &config_dir(&self.config_path));
+						println!("Task {} updated", name);
+						self.tasks.insert(name, new_task);
+					}
+					_ => { println!("Task {} removed", name); }
+				}
+			}
+		}
+	}
+
 	pub fn print_status(&mut self, args: Vec<ProcessArg>) {
 		println!("[Task Name]\t-\t[Status]\t-\t[Info]\t-\t[Uptime]");
 		println!("------------------------------------------------------------------------");
 		if args.is_empty() {
-			for (_name, task) in &mut self.tasks {
+			for task in self.tasks.values_mut() {
 				task.print_processes("*".to_string());
 			}
 		} else {
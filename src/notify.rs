@@ -0,0 +1,60 @@
+use std::{fs::{self, DirBuilder}, io, os::unix::{fs::{DirBuilderExt, PermissionsExt}, io::{AsRawFd, RawFd}, net::UnixDatagram}, path::PathBuf};
+use crate::credentials::Credentials;
+
+// sd_notify-style socket of one process: the child finds its path in
+// NOTIFY_SOCKET and sends datagrams like "READY=1\nSTATUS=Listening".
+#[derive(Debug)]
+pub struct NotifySocket {
+	pub path: PathBuf,
+	socket: UnixDatagram,
+}
+
+impl NotifySocket {
+	pub fn bind(name: &str, credentials: Option<&Credentials>) -> io::Result<NotifySocket> {
+		let dir = runtime_dir();
+		DirBuilder::new().recursive(true).mode(0o711).create(&dir)?;
+		let path = dir.join(format!("{}.sock", name));
+		let _ = fs::remove_file(&path);
+		let socket = UnixDatagram::bind(&path)?;
+		socket.set_nonblocking(true)?;
+		// Only the child may write to it, whichever user it runs as.
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+		if let Some(credentials) = credentials {
+			std::os::unix::fs::chown(&path, credentials.uid, credentials.gid)?;
+		}
+		Ok(NotifySocket { path, socket })
+	}
+
+	pub fn fd(&self) -> RawFd {
+		self.socket.as_raw_fd()
+	}
+
+	// Every pending message as KEY=VALUE pairs.
+	pub fn receive(&self) -> Vec<(String, String)> {
+		let mut pairs = vec![];
+		let mut buf = [0u8; 4096];
+		while let Ok(n) = self.socket.recv(&mut buf) {
+			let message = String::from_utf8_lossy(&buf[..n]);
+			pairs.extend(message.lines().filter_map(|line| {
+				let (key, value) = line.split_once('=')?;
+				Some((key.to_string(), value.to_string()))
+			}));
+		}
+		pairs
+	}
+}
+
+impl Drop for NotifySocket {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+fn runtime_dir() -> PathBuf {
+	std::env::temp_dir().join(format!("taskmaster-{}", std::process::id()))
+}
+
+// Removes the socket directory on exit, when destructors do not run.
+pub fn cleanup() {
+	let _ = fs::remove_dir_all(runtime_dir());
+}
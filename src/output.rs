@@ -0,0 +1,188 @@
+use std::{collections::VecDeque, fs::File, io::{self, Read}, os::{fd::OwnedFd, unix::io::{AsRawFd, RawFd}}};
+use libc::{F_GETFL, F_SETFL, O_NONBLOCK};
+
+const MAX_LINE: usize = 64 * 1024;
+// Bytes read from one pipe per wakeup, so a chatty child cannot starve the
+// loop: poll reports the pipe again while data is left.
+const READ_BUDGET: usize = 64 * 1024;
+// Lines and bytes kept per process for `tail`, whichever limit comes first.
+const TAIL_LINES: usize = 1000;
+const TAIL_BYTES: usize = 1 << 20;
+
+// What `tail` shows: one stream or both, labelled with their process when
+// several processes are shown together.
+#[derive(Debug, Clone, Copy)]
+pub struct TailFilter {
+	pub stream: Option<Stream>,
+	pub prefix: bool,
+}
+
+impl TailFilter {
+	pub fn accepts(&self, stream: Stream) -> bool {
+		self.stream.is_none_or(|s| s == stream)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+	Stdout,
+	Stderr,
+}
+
+impl std::fmt::Display for Stream {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Stream::Stdout => write!(f, "stdout"),
+			Stream::Stderr => write!(f, "stderr"),
+		}
+	}
+}
+
+impl Stream {
+	pub fn index(&self) -> usize {
+		match self {
+			Stream::Stdout => 0,
+			Stream::Stderr => 1,
+		}
+	}
+}
+
+// Read end of a child's stdout or stderr pipe, owned by the supervisor.
+#[derive(Debug)]
+pub struct Output {
+	pub stream: Stream,
+	// The child writing to the pipe, for line prefixes.
+	pub pid: u32,
+	pipe: File,
+	// Start of a line whose newline has not been read yet.
+	partial: Vec<u8>,
+}
+
+impl Output {
+	pub fn new(stream: Stream, pid: u32, pipe: impl Into<OwnedFd>) -> Output {
+		let pipe = File::from(pipe.into());
+		set_nonblocking(pipe.as_raw_fd());
+		Output { stream, pid, pipe, partial: vec![] }
+	}
+
+	pub fn fd(&self) -> RawFd {
+		self.pipe.as_raw_fd()
+	}
+
+	// Complete lines of `data` without their newline. The unterminated tail waits for the
+	// next read, unless it grows past MAX_LINE and gets split.
+	pub fn lines(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+		self.partial.extend_from_slice(data);
+		let end = match self.partial.iter().rposition(|&b| b == b'\n') {
+			Some(newline) => newline + 1,
+			None if self.partial.len() >= MAX_LINE => self.partial.len(),
+			None => return vec![],
+		};
+		let rest = self.partial.split_off(end);
+		let complete = std::mem::replace(&mut self.partial, rest);
+		complete.strip_suffix(b"\n").unwrap_or(&complete)
+			.split(|&b| b == b'\n')
+			.map(|line| line.to_vec())
+			.collect()
+	}
+
+	// The unterminated line read so far, e.g. a prompt waiting for input.
+	pub fn pending(&self) -> &[u8] {
+		&self.partial
+	}
+
+	// What is left of an unterminated last line once the pipe is closed.
+	pub fn take_partial(&mut self) -> Option<Vec<u8>> {
+		if self.partial.is_empty() {
+			return None;
+		}
+		Some(std::mem::take(&mut self.partial))
+	}
+
+	// What is buffered in the pipe, up to READ_BUDGET; None once the write end is closed.
+	pub fn read(&mut self) -> Option<Vec<u8>> {
+		let mut data = vec![];
+		let mut buf = [0u8; 8192];
+		while data.len() < READ_BUDGET {
+			match self.pipe.read(&mut buf) {
+				Ok(0) => return if data.is_empty() { None } else { Some(data) },
+				Ok(n) => data.extend_from_slice(&buf[..n]),
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(_) => return None,
+			}
+		}
+		Some(data)
+	}
+}
+
+// The last lines of a process, for `tail`; the oldest go first.
+#[derive(Debug, Default)]
+pub struct Tail {
+	lines: VecDeque<(Stream, Vec<u8>)>,
+	bytes: usize,
+}
+
+impl Tail {
+	pub fn push(&mut self, stream: Stream, line: Vec<u8>) {
+		self.bytes += line.len();
+		self.lines.push_back((stream, line));
+		while self.lines.len() > TAIL_LINES || self.bytes > TAIL_BYTES {
+			let Some((_, old)) = self.lines.pop_front() else { break };
+			self.bytes -= old.len();
+		}
+	}
+
+	pub fn iter(&self) -> impl DoubleEndedIterator<Item = &(Stream, Vec<u8>)> {
+		self.lines.iter()
+	}
+}
+
+// The supervisor never blocks on a child's pipe, whichever way it goes.
+pub fn set_nonblocking(fd: RawFd) {
+	unsafe { libc::fcntl(fd, F_SETFL, libc::fcntl(fd, F_GETFL) | O_NONBLOCK) };
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn output() -> Output {
+		let (reader, _) = io::pipe().unwrap();
+		Output::new(Stream::Stdout, 0, reader)
+	}
+
+	#[test]
+	fn lines_wait_for_their_newline() {
+		let mut output = output();
+		assert_eq!(output.lines(b"one\ntw"), vec![b"one".to_vec()]);
+		assert_eq!(output.pending(), b"tw");
+		assert_eq!(output.lines(b"o\nthree\n\nfour"), vec![b"two".to_vec(), b"three".to_vec(), b"".to_vec()]);
+		assert_eq!(output.take_partial(), Some(b"four".to_vec()));
+		assert_eq!(output.take_partial(), None);
+	}
+
+	#[test]
+	fn long_lines_are_split() {
+		let mut output = output();
+		assert!(output.lines(&vec![b'x'; MAX_LINE - 1]).is_empty());
+		let lines = output.lines(b"xx");
+		assert_eq!(lines, vec![vec![b'x'; MAX_LINE + 1]]);
+		assert!(output.pending().is_empty());
+	}
+
+	#[test]
+	fn tail_is_bounded_by_lines_and_bytes() {
+		let mut tail = Tail::default();
+		for i in 0..TAIL_LINES + 10 {
+			tail.push(Stream::Stdout, i.to_string().into_bytes());
+		}
+		assert_eq!(tail.iter().count(), TAIL_LINES);
+		assert_eq!(tail.iter().next().unwrap().1, b"10");
+		for _ in 0..100 {
+			tail.push(Stream::Stderr, vec![b'x'; MAX_LINE]);
+		}
+		assert_eq!(tail.iter().count(), TAIL_BYTES / MAX_LINE);
+		assert!(tail.iter().all(|(stream, _)| *stream == Stream::Stderr));
+	}
+}
@@ -0,0 +1,75 @@
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+// `alert_patterns:` entry: a regex, or { pattern, restart } to also restart the process.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged, expecting = "expected a regex or { pattern, restart }")]
+pub enum AlertPattern {
+	Pattern(String),
+	Full {
+		pattern: String,
+		#[serde(default)]
+		restart: bool,
+	},
+}
+
+impl AlertPattern {
+	fn parts(&self) -> (&str, bool) {
+		match self {
+			AlertPattern::Pattern(pattern) => (pattern, false),
+			AlertPattern::Full { pattern, restart } => (pattern, *restart),
+		}
+	}
+}
+
+impl std::fmt::Display for AlertPattern {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let (pattern, restart) = self.parts();
+		write!(f, "/{}/{}", pattern, if restart { " (restart)" } else { "" })
+	}
+}
+
+// What a line of captured output matched.
+pub enum Match {
+	Ready,
+	Alert { pattern: String, restart: bool },
+}
+
+// The compiled ready_pattern and alert_patterns of a task.
+#[derive(Debug, Clone)]
+pub struct Patterns {
+	ready: Option<Regex>,
+	alerts: Vec<(Regex, bool)>,
+}
+
+impl Patterns {
+	pub fn compile(ready: &Option<String>, alerts: &[AlertPattern]) -> Result<Option<Patterns>, String> {
+		if ready.is_none() && alerts.is_empty() {
+			return Ok(None);
+		}
+		let ready = match ready {
+			Some(pattern) => Some(Regex::new(pattern).map_err(|e| format!("ready_pattern: {}", e))?),
+			None => None,
+		};
+		let alerts = alerts.iter()
+			.map(|alert| {
+				let (pattern, restart) = alert.parts();
+				Regex::new(pattern).map(|regex| (regex, restart)).map_err(|e| format!("alert_patterns: {}", e))
+			})
+			.collect::<Result<Vec<_>, String>>()?;
+		Ok(Some(Patterns { ready, alerts }))
+	}
+
+	pub fn matches(&self, line: &str) -> Vec<Match> {
+		let mut matches = vec![];
+		if self.ready.as_ref().is_some_and(|ready| ready.is_match(line)) {
+			matches.push(Match::Ready);
+		}
+		for (regex, restart) in &self.alerts {
+			if regex.is_match(line) {
+				matches.push(Match::Alert { pattern: regex.to_string(), restart: *restart });
+			}
+		}
+		matches
+	}
+}
@@ -1,14 +1,21 @@
-use std::{process::{Child, Command}, time::{Instant, Duration}, error::Error};
-use libc::{self, mode_t, umask};
-use crate::{task_utils::{sigtype_to_string, Sigtype, Config}};
+use std::{collections::VecDeque, fs::{self, File}, io::{PipeReader, Write}, os::{fd::{AsRawFd, OwnedFd}, unix::io::RawFd}, process::{Child, Command, ExitStatus, Stdio}, time::{Instant, Duration}, error::Error, io, os::unix::process::{CommandExt, ExitStatusExt}};
+use libc::{c_int, pid_t, SIGKILL, ESRCH};
+use crate::{cgroup::Cgroup, child_setup::ChildSetup, health::{HealthState, Probe}, notify::NotifySocket, logger::{LogFormat, LogSink, Origin}, output::{set_nonblocking, Output, Stream, Tail, TailFilter}, pty::Pty, patterns::{Match, Patterns}, task_utils::{Sigtype, Stdin, StopStep, Config}};
+
+// Delay before sending SIGKILL again when it could not be sent.
+const KILL_RETRY: Duration = Duration::from_secs(1);
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Status {
     Starting,
     Running,
+    // Running, but failing its health check.
+    Unhealthy,
     Stopping,
     Stopped,
     Restarting,
+    // Died while starting, waiting for `retry_at` to try again.
+    Backoff,
     Fatal,
 }
 
@@ -16,31 +23,91 @@ pub enum Status {
 pub struct Process {
     pub id: u32,
     cmd: Command,
-    umask: u32,
     task_name: String,
-    stop_sig: Sigtype,
+    stop_sequence: Vec<StopStep>,
+    pub stop_step: usize,
+    stop_as_group: bool,
+    kill_as_group: bool,
     pub child: Option<Child>,
     pub status: Status,
     pub retries: u32,
     pub timer: Instant,
     pub uptime: Instant,
+    // When a process in Backoff, or Fatal and cooling down, starts again.
+    pub retry_at: Option<Instant>,
+    // Automatic restarts within the last restart_window.
+    restarts: VecDeque<Instant>,
+    // Set while stopped for using up max_restarts: the stop ends Fatal, with this reason.
+    fatal_on_stop: Option<String>,
+    pub health: Option<HealthState>,
+    // The health check, run with this process's user, directory and environment.
+    pub probe: Option<Probe>,
+    pub notify: Option<NotifySocket>,
+    pub patterns: Option<Patterns>,
+    // Last STATUS= sent on the notify socket.
+    pub status_text: Option<String>,
+    watchdog_at: Option<Instant>,
     pub error: Option<Box<dyn Error>>,
+    pub exit_reason: Option<String>,
+    pub exit_code: Option<i32>,
+    pub signal_error: Option<io::Error>,
+    pub cgroup: Option<Cgroup>,
+    pub outputs: Vec<Output>,
+    // Index in the task's sinks for stdout and stderr.
+    pub sinks: [Option<usize>; 2],
+    // Stdout and stderr go through one pipe, see merge_output.
+    pub merge_streams: bool,
+    pub log_format: LogFormat,
+    // Last lines of output, for `tail`.
+    pub tail: Tail,
+    // Set while the shell follows this process's output.
+    pub follow: Option<TailFilter>,
+    pub stdin_mode: Stdin,
+    // Where input for the child is written, with stdin: pipe or pty.
+    pub stdin: Option<File>,
+    // Set while the shell is attached: output is echoed as it arrives.
+    pub attached: bool,
 }
 
 impl Process {
-    pub fn new(id: u32, task_name: String, cmd: Command, umask: u32, stop_sig: Sigtype) -> Process {
+    pub fn new(id: u32, task_name: String, mut cmd: Command, stop_sequence: Vec<StopStep>, setup: ChildSetup, stop_as_group: bool, kill_as_group: bool) -> Process {
+        unsafe { cmd.pre_exec(move || setup.apply()) };
         Process {
             id,
             cmd,
-            umask,
-            stop_sig,
+            stop_sequence,
+            stop_step: 0,
+            stop_as_group,
+            kill_as_group: kill_as_group || stop_as_group,
             child: None,
             task_name,
             status: Status::Stopped,
             retries: 0,
             timer: Instant::now(),
             uptime: Instant::now(),
-            error: None
+            retry_at: None,
+            restarts: VecDeque::new(),
+            fatal_on_stop: None,
+            health: None,
+            probe: None,
+            notify: None,
+            patterns: None,
+            status_text: None,
+            watchdog_at: None,
+            error: None,
+            exit_reason: None,
+            exit_code: None,
+            signal_error: None,
+            cgroup: None,
+            outputs: vec![],
+            sinks: [None, None],
+            merge_streams: false,
+            log_format: LogFormat::Raw,
+            tail: Tail::default(),
+            follow: None,
+            stdin_mode: Stdin::Null,
+            stdin: None,
+            attached: false,
         }
     }
 
@@ -48,82 +115,521 @@ impl Process {
         if let Some(_child) = &self.child {
             return println!("Process {}:{} is already running", self.task_name, self.id);
         }
+        self.retry_at = None;
+        self.status_text = None;
+        self.exit_code = None;
+        self.fatal_on_stop = None;
         if self.error.is_none() {
-            let old_umask = self.set_umask(self.umask);
-            match self.cmd.spawn() {
-                Ok(child) => {
+            let spawned = self.spawn();
+            match spawned {
+                Ok(mut child) => {
+                    if let Some(stdout) = child.stdout.take() {
+                        self.outputs.push(Output::new(Stream::Stdout, child.id(), stdout));
+                    }
+                    if let Some(stderr) = child.stderr.take() {
+                        self.outputs.push(Output::new(Stream::Stderr, child.id(), stderr));
+                    }
                     self.status = Status::Starting;
                     self.timer = Instant::now();
                     self.child = Some(child);
                 }
-                Err(error) => { self.error = Some(Box::new(error)) }
+                Err(error) => {
+                    self.status = Status::Fatal;
+                    self.error = Some(match error.raw_os_error() {
+                        Some(libc::EPERM) => Box::new(io::Error::new(error.kind(), format!("child setup failed (user, group, nice or rlimits): {}", error))),
+                        _ => Box::new(error),
+                    });
+                }
             }
-            self.set_umask(old_umask);
         } else {
             self.status = Status::Fatal;
         }
         self.retries += 1;
     }
 
+    fn spawn(&mut self) -> io::Result<Child> {
+        let merged = self.merge_output()?;
+        let terminal = self.connect_stdin()?;
+        let spawned = self.cmd.spawn();
+        // Drop the command's copies of the child's ends, or the supervisor never sees EOF.
+        self.cmd.stdin(Stdio::null());
+        if merged.is_some() || terminal.is_some() {
+            self.cmd.stdout(Stdio::null());
+            self.cmd.stderr(Stdio::null());
+        }
+        let mut child = spawned?;
+        self.stdin = None;
+        if let Some(reader) = merged {
+            self.outputs.push(Output::new(Stream::Stdout, child.id(), reader));
+        }
+        if let Some((reader, writer)) = terminal {
+            self.outputs.push(Output::new(Stream::Stdout, child.id(), reader));
+            self.stdin = Some(writer);
+        }
+        if let Some(stdin) = child.stdin.take() {
+            let stdin = File::from(OwnedFd::from(stdin));
+            set_nonblocking(stdin.as_raw_fd());
+            self.stdin = Some(stdin);
+        }
+        Ok(child)
+    }
+
+    // Sets up stdin for the next spawn. With stdin: pty the child's three streams are
+    // a fresh pty slave, and the master comes back once to read from and once to write to.
+    fn connect_stdin(&mut self) -> io::Result<Option<(File, File)>> {
+        match &self.stdin_mode {
+            Stdin::Null => { self.cmd.stdin(Stdio::null()); }
+            Stdin::File(path) => {
+                let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("stdin {}: {}", path, e)))?;
+                self.cmd.stdin(file);
+            }
+            Stdin::Pipe => { self.cmd.stdin(Stdio::piped()); }
+            Stdin::Pty => {
+                let pty = Pty::open()?;
+                self.cmd.stdin(pty.slave.try_clone()?);
+                self.cmd.stdout(pty.slave.try_clone()?);
+                self.cmd.stderr(pty.slave);
+                let writer = pty.master.try_clone()?;
+                return Ok(Some((pty.master, writer)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.task_name, self.id)
+    }
+
+    // Starts echoing output to the shell, beginning with the current prompt if any.
+    pub fn attach(&mut self) {
+        self.attached = true;
+        let mut stdout = io::stdout();
+        for output in &self.outputs {
+            let _ = stdout.write_all(output.pending());
+        }
+        let _ = stdout.flush();
+    }
+
+    // Input typed in the shell while attached.
+    pub fn write_stdin(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.write_all(data),
+            None => Err(io::Error::other("stdin is not a pipe (set stdin: pipe or pty)")),
+        }
+    }
+
+    // With redirect_stderr and raw logs both streams share one pipe, so lines keep the
+    // order the child wrote them in. The pipe is made per spawn since it closes with the child.
+    fn merge_output(&mut self) -> io::Result<Option<PipeReader>> {
+        if !self.merge_streams {
+            return Ok(None);
+        }
+        let (reader, writer) = io::pipe()?;
+        self.cmd.stdout(writer.try_clone()?);
+        self.cmd.stderr(writer);
+        Ok(Some(reader))
+    }
+
+    // Delivers sig to the child, or to its whole process group (the child is a
+    // session leader, so its pgid is its pid). A child that is already gone (ESRCH)
+    // counts as delivered: it is about to be reaped anyway.
+    fn send_signal(&mut self, sig: c_int, group: bool) -> bool {
+        let Some(child) = &self.child else { return false };
+        let pid = child.id() as pid_t;
+        let ret = if group { unsafe { libc::killpg(pid, sig) } } else { unsafe { libc::kill(pid, sig) } };
+        if ret != 0 {
+            let error = io::Error::last_os_error();
+            // Reported once, not on every retry; status keeps showing it.
+            if self.signal_error.as_ref().map(|e| e.kind()) != Some(error.kind()) {
+                eprintln!("{}:{}: cannot send signal {}: {}", self.task_name, self.id, sig, error);
+            }
+            let gone = error.raw_os_error() == Some(ESRCH);
+            self.signal_error = Some(error);
+            return gone;
+        }
+        self.signal_error = None;
+        true
+    }
+
     pub fn stop(&mut self) {
-        if self.status == Status::Stopping { return; }
-        if let Some(child) = &self.child {
-            let mut kill_cmd = Command::new("kill");
-            match kill_cmd.args(["-s", sigtype_to_string(&self.stop_sig), child.id().to_string().as_str()]).output() {
-                Ok(_) => {
-                    self.timer = Instant::now();
-                    self.status = Status::Stopping;
-                }
-                Err(e) => { eprintln!("{}", e) }
+        match self.status {
+            Status::Stopping => return,
+            // Already going through the stop sequence: just don't start again afterwards.
+            Status::Restarting => { self.status = Status::Stopping; return; }
+            _ if self.child.is_none() && self.retry_at.is_some() => {
+                self.retry_at = None;
+                self.status = Status::Stopped;
+                return println!("{}:{} is now stopped", self.task_name, self.id);
             }
+            _ => {}
         }
+        if self.child.is_none() {
+            return;
+        }
+        self.stop_step = 0;
+        self.send_stop_step();
+        self.status = Status::Stopping;
+    }
+
+    // Sends the current step's signal and starts its wait. The wait runs even when
+    // the signal cannot be sent, so the sequence still moves on and ends in SIGKILL.
+    fn send_stop_step(&mut self) {
+        let sig = self.stop_sequence[self.stop_step].signal.number();
+        self.send_signal(sig, self.stop_as_group);
+        self.timer = Instant::now();
+    }
+
+    pub fn current_stop_step(&self) -> &StopStep {
+        &self.stop_sequence[self.stop_step]
+    }
+
+    pub fn stop_steps(&self) -> usize {
+        self.stop_sequence.len()
+    }
+
+    // Moves to the next stop_sequence step; false once the sequence is exhausted.
+    fn escalate(&mut self) -> bool {
+        if self.stop_step + 1 >= self.stop_sequence.len() {
+            return false;
+        }
+        self.stop_step += 1;
+        println!("{}:{} still running, sending {}", self.task_name, self.id, self.current_stop_step().signal);
+        self.send_stop_step();
+        true
     }
 
     pub fn restart(&mut self) {
+        // Nothing to stop first: just start, so a waiting retry does not sit in Restarting.
+        if self.child.is_none() {
+            return self.start();
+        }
         self.stop();
         self.status = Status::Restarting;
     }
 
-    pub fn kill(&mut self) {
-        if let Some(child) = &mut self.child {
-            match child.kill() {
-                Ok(_) => {
-                    self.child = None;
-                    self.status = Status::Stopped;
+    // SIGKILL then reap right away, so no zombie is left behind. Returns whether the
+    // child is gone; when SIGKILL cannot be sent the error is kept in signal_error.
+    pub fn kill(&mut self) -> bool {
+        // Collected first: once the child is reaped its descendants get reparented.
+        let descendants = if self.kill_as_group { self.descendants() } else { vec![] };
+        if self.child.is_none() {
+            return true;
+        }
+        if !self.send_signal(SIGKILL, self.kill_as_group) {
+            // Push the timer ahead so the next attempt waits a second more than the
+            // usual deadline instead of coming on every wakeup.
+            self.timer = Instant::now() + KILL_RETRY;
+            return false;
+        }
+        // Descendants that left the process group are not reached by killpg.
+        for pid in descendants {
+            unsafe { libc::kill(pid, SIGKILL) };
+        }
+        if let Some(mut child) = self.child.take() {
+            match child.wait() {
+                Ok(status) => self.set_exit_reason(status, false),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        self.set_stopped();
+        true
+    }
+
+    pub fn set_stopped(&mut self) {
+        self.status = Status::Stopped;
+        if let Some(reason) = self.fatal_on_stop.take() {
+            self.status = Status::Fatal;
+            self.exit_reason = Some(reason);
+        }
+    }
+
+    // Forwards pipe data to the log sinks. With `ready` only those fds are read,
+    // without it every pipe is (used when the child is reaped); poll reports the
+    // pipes again while data is left.
+    pub fn read_outputs(&mut self, ready: Option<&[RawFd]>, sinks: &mut [LogSink], config: &Config) {
+        let format = self.log_format;
+        let mut matches = vec![];
+        self.outputs.retain_mut(|output| {
+            if ready.is_some_and(|ready| !ready.contains(&output.fd())) {
+                return true;
+            }
+            let data = output.read();
+            let mut lines = output.lines(data.as_deref().unwrap_or_default());
+            if data.is_none() {
+                lines.extend(output.take_partial());
+            }
+            if let Some(sink) = self.sinks[output.stream.index()] {
+                let origin = Origin { task: &self.task_name, id: self.id, pid: output.pid, stream: output.stream };
+                match (format, &data) {
+                    (LogFormat::Raw, Some(data)) => sinks[sink].write(data),
+                    (LogFormat::Raw, None) => {}
+                    _ => lines.iter().for_each(|line| sinks[sink].write(&format.format(&origin, line))),
                 }
-                Err(e) => { eprintln!("{}", e) }
             }
+            if self.attached {
+                if let Some(data) = &data {
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(data).and_then(|_| stdout.flush());
+                }
+            }
+            for line in lines {
+                if let Some(patterns) = &self.patterns {
+                    let text = String::from_utf8_lossy(&line);
+                    matches.extend(patterns.matches(&text).into_iter().map(|m| (m, text.to_string())));
+                }
+                if let Some(filter) = self.follow.filter(|f| f.accepts(output.stream)) {
+                    print_line(&self.task_name, self.id, filter, &line);
+                }
+                self.tail.push(output.stream, line);
+            }
+            data.is_some()
+        });
+        for (matched, line) in matches {
+            self.on_match(matched, &line, config);
         }
     }
-    
-    fn set_umask(&self, new_umask: libc::mode_t) -> mode_t {
-        let old_umask = unsafe { umask(new_umask) };
-        old_umask
+
+    fn on_match(&mut self, matched: Match, line: &str, config: &Config) {
+        match matched {
+            Match::Ready if self.status == Status::Starting && self.child.is_some() => self.set_running(config),
+            Match::Ready => {}
+            Match::Alert { pattern, restart } => {
+                println!("{}:{} alert /{}/: {}", self.task_name, self.id, pattern, line);
+                if restart && matches!(self.status, Status::Running | Status::Unhealthy) {
+                    self.restart_automatically(config);
+                }
+            }
+        }
     }
 
-    pub fn check_process_state(&mut self, config: &Config) {
+    // The last `count` captured lines that pass the filter.
+    pub fn print_tail(&self, filter: TailFilter, count: usize) {
+        let lines: Vec<&Vec<u8>> = self.tail.iter().rev()
+            .filter(|(stream, _)| filter.accepts(*stream))
+            .take(count)
+            .map(|(_, line)| line)
+            .collect();
+        for line in lines.into_iter().rev() {
+            print_line(&self.task_name, self.id, filter, line);
+        }
+    }
+
+    // PIDs of every live process below the child, from the ppid links in /proc.
+    pub fn descendants(&self) -> Vec<pid_t> {
+        let Some(child) = &self.child else { return vec![] };
+        let Ok(entries) = fs::read_dir("/proc") else { return vec![] };
+        let links: Vec<(pid_t, pid_t)> = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<pid_t>().ok())
+            .filter_map(|pid| Some((pid, parent_pid(pid)?)))
+            .collect();
+        let mut descendants: Vec<pid_t> = vec![];
+        let mut parents: Vec<pid_t> = vec![child.id() as pid_t];
+        while let Some(parent) = parents.pop() {
+            for (pid, _) in links.iter().filter(|(_, ppid)| *ppid == parent) {
+                descendants.push(*pid);
+                parents.push(*pid);
+            }
+        }
+        descendants
+    }
+
+    pub fn set_exit_reason(&mut self, status: ExitStatus, oom_killed: bool) {
+        let reason = match (status.code(), status.signal()) {
+            (_, Some(libc::SIGKILL)) if oom_killed => "killed by the OOM killer".to_string(),
+            (Some(code), _) => format!("exited with code {}", code),
+            (_, Some(signal)) => match Sigtype::from_number(signal) {
+                Ok(sigtype) => format!("killed by {}", sigtype),
+                Err(_) => format!("killed by signal {}", signal),
+            },
+            _ => "exited".to_string(),
+        };
+        println!("{}:{} {}", self.task_name, self.id, reason);
+        self.exit_reason = Some(reason);
+        self.exit_code = status.code();
+    }
+
+    fn stop_wait(&self) -> Duration {
+        Duration::new(self.current_stop_step().wait as u64, 0)
+    }
+
+    pub fn deadline(&self, config: &Config) -> Option<Instant> {
+        if self.child.is_none() {
+            return self.retry_at;
+        }
         match self.status {
-            Status::Starting => {
-                if self.timer.elapsed() > Duration::new(config.starttime as u64, 0) {
-                    self.retries = 0;
+            Status::Starting => Some(self.timer + Duration::new(config.starttime as u64, 0)),
+            Status::Running | Status::Unhealthy => self.health.as_ref().and_then(|health| health.deadline()).into_iter().chain(self.watchdog_at).min(),
+            Status::Stopping | Status::Restarting => Some(self.timer + self.stop_wait()),
+            _ => None,
+        }
+    }
+
+    // Waits `backoff` before the next start, unless the retries are used up.
+    pub fn retry(&mut self, config: &Config) {
+        if self.retries >= config.startretries {
+            self.status = Status::Fatal;
+            return;
+        }
+        let delay = config.backoff.delay(self.retries);
+        let Some(at) = Instant::now().checked_add(delay) else {
+            self.status = Status::Fatal;
+            return;
+        };
+        self.status = Status::Backoff;
+        self.retry_at = Some(at);
+        println!("{}:{} retrying in {:.1}s", self.task_name, self.id, delay.as_secs_f64());
+    }
+
+    // Restarts a process that died after reaching Running, unless it keeps dying.
+    pub fn autorestart(&mut self, config: &Config) {
+        if self.count_restart(config) {
+            self.start();
+        }
+    }
+
+    // Health checks, alert patterns and the watchdog restart a process that is still
+    // alive, from the same budget: once it is spent the process is stopped and left Fatal.
+    fn restart_automatically(&mut self, config: &Config) {
+        if self.count_restart(config) {
+            return self.restart();
+        }
+        self.stop();
+        self.fatal_on_stop = self.exit_reason.clone();
+    }
+
+    // Records an automatic restart. More than max_restarts within restart_window makes
+    // the process Fatal instead, retried after cooldown, and returns false.
+    fn count_restart(&mut self, config: &Config) -> bool {
+        if let Some(max) = config.max_restarts {
+            let now = Instant::now();
+            let window = Duration::from_secs(config.restart_window as u64);
+            self.restarts.retain(|at| now.duration_since(*at) < window);
+            if self.restarts.len() as u32 >= max {
+                self.restarts.clear();
+                self.status = Status::Fatal;
+                let reason = format!("crash loop: {} restarts within {}s", max, config.restart_window);
+                match config.cooldown {
+                    Some(cooldown) => {
+                        self.retry_at = Some(now + Duration::from_secs(cooldown as u64));
+                        println!("{}:{} {}, retrying in {}s", self.task_name, self.id, reason, cooldown);
+                    }
+                    None => println!("{}:{} {}", self.task_name, self.id, reason),
+                }
+                self.exit_reason = Some(reason);
+                return false;
+            }
+            self.restarts.push_back(now);
+        }
+        true
+    }
+
+    fn set_running(&mut self, config: &Config) {
+        self.retries = 0;
+        self.status = Status::Running;
+        self.uptime = Instant::now();
+        self.health = config.healthcheck.as_ref().map(|check| HealthState::new(check.interval));
+        self.watchdog_at = config.watchdog.map(|watchdog| Instant::now() + Duration::from_secs(watchdog as u64));
+        println!("{}:{} is now running", self.task_name, self.id);
+    }
+
+    // Handles READY=1, STATUS= and WATCHDOG=1 from the child.
+    pub fn read_notifications(&mut self, config: &Config) {
+        let Some(notify) = &self.notify else { return };
+        for (key, value) in notify.receive() {
+            match (key.as_str(), value.as_str()) {
+                ("READY", "1") if self.status == Status::Starting && self.child.is_some() => self.set_running(config),
+                ("STATUS", text) => self.status_text = Some(text.to_string()),
+                ("WATCHDOG", "1") if self.watchdog_at.is_some() => {
+                    self.watchdog_at = config.watchdog.map(|watchdog| Instant::now() + Duration::from_secs(watchdog as u64));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Collects the last health check result and launches the next one when due.
+    pub fn check_health(&mut self, config: &Config) {
+        let (Some(check), Some(health)) = (&config.healthcheck, &mut self.health) else { return };
+        if !matches!(self.status, Status::Running | Status::Unhealthy) {
+            return;
+        }
+        match health.poll() {
+            Some(Ok(())) => {
+                health.failures = 0;
+                health.last_error = None;
+                if self.status == Status::Unhealthy {
                     self.status = Status::Running;
-                    self.uptime = Instant::now();
-                    println!("{}:{} is now running", self.task_name, self.id);
+                    println!("{}:{} is healthy again", self.task_name, self.id);
                 }
             }
-            Status::Stopping => {
-                if self.timer.elapsed() > Duration::new(config.stoptime as u64, 0) {
-                    self.kill();
-                    println!("{}:{} is now stopped", self.task_name, self.id);
+            Some(Err(e)) => {
+                health.failures += 1;
+                health.last_error = Some(e.clone());
+                if self.status == Status::Running && health.failures >= check.failures {
+                    self.status = Status::Unhealthy;
+                    println!("{}:{} is unhealthy: {}", self.task_name, self.id, e);
+                    if check.restart {
+                        self.health = None;
+                        return self.restart_automatically(config);
+                    }
                 }
             }
-            Status::Restarting => {
-                if self.timer.elapsed() > Duration::new(config.stoptime as u64, 0) {
-                    self.kill();
+            None => {}
+        }
+        if let Some(probe) = &self.probe {
+            health.launch(probe.clone(), check);
+        }
+    }
+
+    pub fn check_process_state(&mut self, config: &Config) {
+        match self.status {
+            Status::Starting if self.timer.elapsed() > Duration::new(config.starttime as u64, 0) => {
+                if config.waits_for_ready() {
+                    println!("{}:{} did not report ready within {}s", self.task_name, self.id, config.starttime);
+                    if !self.kill() {
+                        return;
+                    }
+                    self.exit_reason = Some("not ready in time".to_string());
+                    return self.retry(config);
+                }
+                self.set_running(config);
+            }
+            Status::Running | Status::Unhealthy if self.watchdog_at.is_some_and(|at| at <= Instant::now()) => {
+                println!("{}:{} missed its watchdog", self.task_name, self.id);
+                self.watchdog_at = None;
+                self.restart_automatically(config);
+            }
+            Status::Stopping | Status::Restarting if self.timer.elapsed() > self.stop_wait() => {
+                if self.escalate() {
+                    return;
+                }
+                let restarting = self.status == Status::Restarting;
+                if !self.kill() {
+                    return;
+                }
+                if restarting {
                     self.start();
+                } else {
+                    println!("{}:{} is now stopped", self.task_name, self.id);
                 }
             }
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
+
+fn print_line(task_name: &str, id: u32, filter: TailFilter, line: &[u8]) {
+    let text = String::from_utf8_lossy(line);
+    if filter.prefix {
+        println!("{}:{} {}", task_name, id, text);
+    } else {
+        println!("{}", text);
+    }
+}
+
+fn parent_pid(pid: pid_t) -> Option<pid_t> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces or parentheses, so start after the last ')'.
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
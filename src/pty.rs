@@ -0,0 +1,43 @@
+use std::{fs::File, io, os::fd::{FromRawFd, AsRawFd}};
+use libc::{O_RDWR, O_NOCTTY, O_CLOEXEC, STDIN_FILENO, TIOCGWINSZ, TIOCSWINSZ, winsize};
+
+// A pseudo-terminal: the child gets the slave as its terminal, the supervisor
+// reads the child's output from the master and writes its input there.
+pub struct Pty {
+	pub master: File,
+	pub slave: File,
+}
+
+impl Pty {
+	pub fn open() -> io::Result<Pty> {
+		let fd = unsafe { libc::posix_openpt(O_RDWR | O_NOCTTY | O_CLOEXEC) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let master = unsafe { File::from_raw_fd(fd) };
+		if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let mut name = [0 as libc::c_char; 128];
+		let ret = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+		if ret != 0 {
+			return Err(io::Error::from_raw_os_error(ret));
+		}
+		let slave_fd = unsafe { libc::open(name.as_ptr(), O_RDWR | O_NOCTTY | O_CLOEXEC) };
+		if slave_fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let slave = unsafe { File::from_raw_fd(slave_fd) };
+		copy_window_size(&master);
+		Ok(Pty { master, slave })
+	}
+}
+
+// Gives the child the size of the supervisor's terminal, so full-screen
+// programs lay themselves out right once attached.
+fn copy_window_size(master: &File) {
+	let mut size: winsize = unsafe { std::mem::zeroed() };
+	if unsafe { libc::ioctl(STDIN_FILENO, TIOCGWINSZ, &mut size) } == 0 {
+		unsafe { libc::ioctl(master.as_raw_fd(), TIOCSWINSZ, &size) };
+	}
+}
@@ -0,0 +1,145 @@
+use std::{fmt, io};
+use serde::{Serialize, Deserialize, Deserializer};
+use libc::{rlim_t, rlimit, RLIM_INFINITY};
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type ResourceId = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type ResourceId = libc::c_int;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+	Nofile,
+	Nproc,
+	Core,
+	As,
+	Cpu,
+	Fsize,
+	Memlock,
+	Stack,
+}
+
+impl Resource {
+	fn id(&self) -> ResourceId {
+		match self {
+			Resource::Nofile => libc::RLIMIT_NOFILE,
+			Resource::Nproc => libc::RLIMIT_NPROC,
+			Resource::Core => libc::RLIMIT_CORE,
+			Resource::As => libc::RLIMIT_AS,
+			Resource::Cpu => libc::RLIMIT_CPU,
+			Resource::Fsize => libc::RLIMIT_FSIZE,
+			Resource::Memlock => libc::RLIMIT_MEMLOCK,
+			Resource::Stack => libc::RLIMIT_STACK,
+		}
+	}
+}
+
+impl fmt::Display for Resource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			Resource::Nofile => "nofile",
+			Resource::Nproc => "nproc",
+			Resource::Core => "core",
+			Resource::As => "as",
+			Resource::Cpu => "cpu",
+			Resource::Fsize => "fsize",
+			Resource::Memlock => "memlock",
+			Resource::Stack => "stack",
+		};
+		write!(f, "{}", name)
+	}
+}
+
+// A single limit: a number or `unlimited`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(untagged)]
+pub enum Limit {
+	Value(u64),
+	#[serde(deserialize_with = "unlimited_deserializer")]
+	Unlimited,
+}
+
+impl Limit {
+	fn as_rlim(&self) -> rlim_t {
+		match self {
+			Limit::Value(value) => *value as rlim_t,
+			Limit::Unlimited => RLIM_INFINITY,
+		}
+	}
+}
+
+impl fmt::Display for Limit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Limit::Value(value) => write!(f, "{}", value),
+			Limit::Unlimited => write!(f, "unlimited"),
+		}
+	}
+}
+
+fn unlimited_deserializer<'de, D>(deserializer: D) -> Result<(), D::Error>
+where
+	D: Deserializer<'de>,
+{
+	match String::deserialize(deserializer)?.as_str() {
+		"unlimited" | "infinity" => Ok(()),
+		other => Err(serde::de::Error::custom(format!("invalid limit '{}'", other))),
+	}
+}
+
+// Either `nofile: 4096` (soft = hard) or `nofile: { soft: 1024, hard: 4096 }`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(untagged, expecting = "expected a number, `unlimited` or { soft, hard }")]
+pub enum RlimitValue {
+	Both(Limit),
+	Split { soft: Limit, hard: Limit },
+}
+
+impl RlimitValue {
+	pub fn soft(&self) -> Limit {
+		match self {
+			RlimitValue::Both(limit) => *limit,
+			RlimitValue::Split { soft, .. } => *soft,
+		}
+	}
+
+	pub fn hard(&self) -> Limit {
+		match self {
+			RlimitValue::Both(limit) => *limit,
+			RlimitValue::Split { hard, .. } => *hard,
+		}
+	}
+
+	pub fn validate(&self) -> Result<(), String> {
+		if self.soft().as_rlim() > self.hard().as_rlim() {
+			return Err(format!("soft limit {} is above hard limit {}", self.soft(), self.hard()));
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rlimit {
+	resource: ResourceId,
+	soft: rlim_t,
+	hard: rlim_t,
+}
+
+impl Rlimit {
+	pub fn new(resource: Resource, value: &RlimitValue) -> Rlimit {
+		Rlimit {
+			resource: resource.id(),
+			soft: value.soft().as_rlim(),
+			hard: value.hard().as_rlim(),
+		}
+	}
+
+	pub fn apply(&self) -> io::Result<()> {
+		let limit = rlimit { rlim_cur: self.soft, rlim_max: self.hard };
+		if unsafe { libc::setrlimit(self.resource, &limit) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
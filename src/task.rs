@@ -1,17 +1,33 @@
-use std::{vec};
+use std::{collections::VecDeque, vec, time::{Duration, Instant}, os::unix::io::RawFd};
 
-use crate::{task_utils::{Config, Autorestart}, process::{Process, Status}, print_process};
+use crate::{cgroup::Cgroup, logger::LogSink, output::TailFilter, task_utils::{Config, Autorestart}, process::{Process, Status}, print_process};
+
+// Transition applied once every process of the task has stopped.
+#[derive(Debug)]
+pub enum PendingUpdate {
+    Replace(Box<Config>),
+    Remove,
+}
 
 #[derive(Debug)]
 pub struct Task {
     pub name: String,
     pub processes: Vec<Process>,
     pub config: Config,
+    pub pending: Option<PendingUpdate>,
+    pub cgroup: Option<Cgroup>,
+    pub sinks: Vec<LogSink>,
+    // Process ids left to autostart, see Monitor::roll_out.
+    pub queued: VecDeque<u32>,
+    // Unmet dependencies holding back the queued processes.
+    pub waiting: Option<String>,
+    // With start_stagger, when the next queued process may start.
+    next_start: Instant,
 }
 
 impl Task {
     pub fn new(config: Config, name: String) -> Task {
-        Task { config, name, processes: vec![] }
+        Task { config, name, processes: vec![], pending: None, cgroup: None, sinks: vec![], queued: VecDeque::new(), waiting: None, next_start: Instant::now() }
     }
 
     fn get_procs_by_id(&mut self, id: String) -> Vec<&mut Process> {
@@ -25,7 +41,16 @@ impl Task {
         procs
     }
     
+    fn is_updating(&self) -> bool {
+        if self.pending.is_some() {
+            eprintln!("Task {} is being updated, try again once it is done", self.name);
+        }
+        self.pending.is_some()
+    }
+
     pub fn start(&mut self, id: String) {
+        if self.is_updating() { return; }
+        self.dequeue(&id);
         let procs = self.get_procs_by_id(id);
         for process in procs {
             process.retries = 0;
@@ -33,14 +58,60 @@ impl Task {
         }
     }
 
+    // A manual start with dependencies unmet: the processes join the rollout and
+    // start once they are met, see Monitor::roll_out.
+    pub fn start_when_met(&mut self, id: String, unmet: String) {
+        if self.is_updating() { return; }
+        let ids: Vec<u32> = self.get_procs_by_id(id).into_iter()
+            .filter(|process| process.child.is_none())
+            .map(|process| { process.retries = 0; process.id })
+            .collect();
+        for id in ids {
+            if !self.queued.contains(&id) {
+                self.queued.push_back(id);
+            }
+        }
+        if !self.queued.is_empty() {
+            println!("Task {}: waiting for {}", self.name, unmet);
+            self.waiting = Some(unmet);
+        }
+    }
+
     pub fn stop(&mut self, id: String) {
+        self.dequeue(&id);
         let procs = self.get_procs_by_id(id);
         for process in procs {
             process.stop();
         }
     }
 
+    // A manual start or stop takes the processes out of the autostart rollout.
+    fn dequeue(&mut self, id: &str) {
+        self.queued.retain(|queued| id != "*" && queued.to_string() != id);
+        if self.queued.is_empty() {
+            self.waiting = None;
+        }
+    }
+
+    // Starts the next queued process. None while start_stagger holds it back,
+    // otherwise whether it is now Starting (false when it failed to spawn).
+    pub fn start_queued(&mut self) -> Option<bool> {
+        let now = Instant::now();
+        if now < self.next_start {
+            return None;
+        }
+        let id = self.queued.pop_front()?;
+        let Some(process) = self.processes.iter_mut().find(|p| p.id == id) else { return Some(false) };
+        process.start();
+        if process.status != Status::Starting {
+            return Some(false);
+        }
+        self.next_start = now + Duration::try_from_secs_f64(self.config.start_stagger).unwrap_or_default();
+        Some(true)
+    }
+
     pub fn restart(&mut self, id: String) {
+        if self.is_updating() { return; }
         let procs = self.get_procs_by_id(id);
         for process in procs {
             process.retries = 0;
@@ -58,10 +129,12 @@ impl Task {
         for proc in procs {
             let status = match proc.status {
                 Status::Running => "\x1B[32mRunning\x1B[0m",
+                Status::Unhealthy => "\x1B[31mUnhealthy\x1B[0m",
                 Status::Stopping => "\x1B[31mStopping\x1B[0m",
                 Status::Stopped => "\x1b[30mStopped\x1B[0m",
                 Status::Restarting => "\x1B[33mRestarting\x1B[0m",
                 Status::Fatal => "\x1B[31mFatal\x1B[0m",
+                Status::Backoff => "\x1B[33mBackoff\x1B[0m",
                 _ => "\x1B[33mStarting\x1B[0m",
             };
             let format = if self.config.numprocs > 1 { format!("{}:{}", self.name, proc.id) }
@@ -77,15 +150,54 @@ impl Task {
                     (uptime.as_secs() / 60) % 60, 
                     uptime.as_secs() % 60
                 );
-                if proc.status == Status::Running {
-                    print_process!(format, status, child.id(), uptime_formatted);
+                let mut pid = child.id().to_string();
+                if matches!(proc.status, Status::Stopping | Status::Restarting) {
+                    pid = format!("{} (step {}/{}: {})", pid, proc.stop_step + 1, proc.stop_steps(), proc.current_stop_step().signal);
+                }
+                if let Some(e) = &proc.signal_error {
+                    pid = format!("{} (signal failed: {})", pid, e);
+                }
+                if let Some(e) = proc.health.as_ref().and_then(|h| h.last_error.as_ref()) {
+                    pid = format!("{} (health check: {})", pid, e);
+                }
+                if matches!(proc.status, Status::Running | Status::Unhealthy) {
+                    print_process!(format, status, pid, uptime_formatted);
                 } else {
-                    print_process!(format, status, child.id());
+                    print_process!(format, status, pid);
                 }
+            } else if let Some(at) = proc.retry_at {
+                let wait = at.saturating_duration_since(Instant::now()).as_secs_f64();
+                let reason = proc.exit_reason.as_deref().unwrap_or("died");
+                let info = match proc.status {
+                    Status::Backoff => format!("{}, attempt {}/{} in {:.1}s", reason, proc.retries + 1, self.config.startretries, wait),
+                    _ => format!("{}, retrying in {:.0}s", reason, wait),
+                };
+                print_process!(format, status, info);
+            } else if self.queued.contains(&proc.id) {
+                let info = match &self.waiting {
+                    Some(deps) => format!("waiting for {}", deps),
+                    None => "queued to start".to_string(),
+                };
+                print_process!(format, status, info);
+            } else if let Some(reason) = &proc.exit_reason {
+                print_process!(format, status, reason);
             } else {
                 print_process!(format, status);
             }
-
+            if let Some(text) = &proc.status_text {
+                println!("\tstatus: {}", text);
+            }
+            let descendants = proc.descendants();
+            if !descendants.is_empty() {
+                let pids: Vec<String> = descendants.iter().map(|pid| pid.to_string()).collect();
+                println!("\tdescendants: {}", pids.join(", "));
+            }
+            if let Some(cgroup) = &proc.cgroup {
+                println!("\tcgroup: {}", cgroup.usage());
+            }
+        }
+        if let Some(cgroup) = self.cgroup.as_ref().filter(|_| !self.config.cgroup_per_process) {
+            println!("\tcgroup: {}", cgroup.usage());
         }
 	}
 
@@ -94,26 +206,25 @@ impl Task {
             if let Some(child) = &mut process.child {
                 match child.try_wait() {
                     Ok(Some(status)) => {
-                        println!("exit status: {:?}, Process status: {:?}", status.code(), process.status);
+                        let oom_killed = match process.cgroup.as_mut().or(self.cgroup.as_mut()) {
+                            Some(cgroup) => cgroup.take_oom_kill(),
+                            None => false,
+                        };
+                        process.read_outputs(None, &mut self.sinks, &self.config);
+                        process.set_exit_reason(status, oom_killed);
                         process.child = None;
                         match process.status {
-                            Status::Starting => {
-                                if process.retries < self.config.startretries {
-                                    process.start();
-                                } else {
-                                    process.status = Status::Fatal;
-                                }
-                            }
-                            Status::Stopping => { process.status = Status::Stopped }
+                            Status::Starting => process.retry(&self.config),
+                            Status::Stopping => process.set_stopped(),
                             Status::Restarting => {	process.start(); }
                             _ => {
                                 match self.config.autorestart {
                                     Autorestart::Always => {
-                                        process.start();
+                                        process.autorestart(&self.config);
                                     }
                                     Autorestart::Unexpected => {
                                         if status.code().is_none() || !self.config.exitcodes.contains(&status.code().unwrap_or(0)) {
-                                            process.start();
+                                            process.autorestart(&self.config);
                                         } else {
                                             process.status = Status::Stopped;
                                         }
@@ -125,24 +236,88 @@ impl Task {
                     }
                     Ok(None) => {
                         process.check_process_state(&self.config);
+                        process.check_health(&self.config);
                     }
                     Err(e) => println!("error attempting to wait: {}", e),
                 }
+            } else if process.retry_at.is_some_and(|at| at <= Instant::now()) {
+                process.start();
             }
         }
     }
 
-    pub fn wait_procs_to_stop(&mut self) {
-        loop {
-            self.try_wait();
-            if !self.processes.iter().any(|p| {
-				p.status != Status::Stopped && p.status != Status::Fatal
-			}) {
-                break;
+    pub fn next_deadline(&self) -> Option<Instant> {
+        // A stagger that already passed is waiting on max_concurrent_starts: a
+        // process leaving Starting wakes the loop then.
+        let stagger = Some(self.next_start).filter(|at| !self.queued.is_empty() && self.waiting.is_none() && *at > Instant::now());
+        self.processes.iter().filter_map(|p| p.deadline(&self.config)).chain(stagger).min()
+    }
+
+    // Marks the task to be replaced or removed and starts draining its processes.
+    pub fn schedule_update(&mut self, update: PendingUpdate) {
+        self.stop("*".to_string());
+        self.pending = Some(update);
+    }
+
+    pub fn is_drained(&self) -> bool {
+        self.processes.iter().all(|p| p.child.is_none())
+    }
+
+    // Output pipes and notify sockets the loop waits on.
+    pub fn watched_fds(&self) -> Vec<RawFd> {
+        self.processes.iter().flat_map(|p| {
+            p.outputs.iter().map(|o| o.fd()).chain(p.notify.as_ref().map(|n| n.fd()))
+        }).collect()
+    }
+
+    pub fn read_outputs(&mut self, ready: &[RawFd]) {
+        for process in self.processes.iter_mut() {
+            process.read_outputs(Some(ready), &mut self.sinks, &self.config);
+            if process.notify.as_ref().is_some_and(|n| ready.contains(&n.fd())) {
+                process.read_notifications(&self.config);
+            }
+        }
+    }
+
+    pub fn tail(&mut self, id: String, filter: TailFilter, count: usize) {
+        for process in self.get_procs_by_id(id) {
+            process.print_tail(filter, count);
+        }
+    }
+
+    // Returns whether any process is now being followed.
+    pub fn follow(&mut self, id: String, filter: TailFilter) -> bool {
+        let procs = self.get_procs_by_id(id);
+        let found = !procs.is_empty();
+        for process in procs {
+            process.follow = Some(filter);
+        }
+        found
+    }
+
+    pub fn unfollow(&mut self) {
+        for process in self.processes.iter_mut() {
+            process.follow = None;
+        }
+    }
+
+    pub fn reopen_logs(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.reopen() {
+                eprintln!("{}: {}", sink.path(), e);
             }
         }
     }
 
+    pub fn remove_cgroups(&self) {
+        for cgroup in self.processes.iter().filter_map(|p| p.cgroup.as_ref()) {
+            cgroup.remove();
+        }
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.remove();
+        }
+    }
+
     pub fn kill(&mut self) {
         for proc in &mut self.processes {
             proc.kill();
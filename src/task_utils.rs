@@ -1,8 +1,8 @@
-use std::{collections::{HashMap, BTreeMap}};
+use std::{collections::{BTreeMap, HashMap, hash_map::RandomState}, error::Error, hash::{BuildHasher, Hasher}, path::{Path, PathBuf}, time::Duration};
 use serde::{Serialize, Deserialize, Deserializer};
+use libc::c_int;
 
-use crate::{process::Status, task::Task};
-use crate::{Process};
+use crate::{cgroup::{self, CgroupValue}, dependency::Dependency, health::HealthCheck, logger::{LogConfig, LogFormat}, patterns::{AlertPattern, Patterns}, template::Vars, credentials::Credentials, rlimits::{Resource, RlimitValue}};
 
 #[macro_export]
 macro_rules! print_process {
@@ -25,7 +25,8 @@ pub enum Autorestart {
 	Never,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Sigtype {
 	HUP,
 	INT,
@@ -33,6 +34,7 @@ pub enum Sigtype {
 	ILL,
 	TRAP,
 	ABRT,
+	#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
 	EMT,
 	FPE,
 	KILL,
@@ -55,51 +57,374 @@ pub enum Sigtype {
 	VTALRM,
 	PROF,
 	WINCH,
+	#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
 	INFO,
 	USR1,
 	USR2,
+	#[cfg(target_os = "linux")]
+	STKFLT,
+	#[cfg(target_os = "linux")]
+	PWR,
+	// SIGRTMIN+n
+	#[cfg(target_os = "linux")]
+	RT(c_int),
 }
 
-pub fn sigtype_to_string(sigtype: &Sigtype) -> &'static str {
-	match sigtype {
-		Sigtype::HUP => "HUP",
-		Sigtype::INT => "INT",
-		Sigtype::QUIT => "QUIT",
-		Sigtype::ILL => "ILL",
-		Sigtype::TRAP => "TRAP",
-		Sigtype::ABRT => "ABRT",
-		Sigtype::EMT => "EMT",
-		Sigtype::FPE => "FPE",
-		Sigtype::KILL => "KILL",
-		Sigtype::BUS => "BUS",
-		Sigtype::SEGV => "SEGV",
-		Sigtype::SYS => "SYS",
-		Sigtype::PIPE => "PIPE",
-		Sigtype::ALRM => "ALRM",
-		Sigtype::TERM => "TERM",
-		Sigtype::URG => "URG",
-		Sigtype::STOP => "STOP",
-		Sigtype::TSTP => "TSTP",
-		Sigtype::CONT => "CONT",
-		Sigtype::CHLD => "CHLD",
-		Sigtype::TTIN => "TTIN",
-		Sigtype::TTOU => "TTOU",
-		Sigtype::IO => "IO",
-		Sigtype::XCPU => "XCPU",
-		Sigtype::XFSZ => "XFSZ",
-		Sigtype::VTALRM => "VTALRM",
-		Sigtype::PROF => "PROF",
-		Sigtype::WINCH => "WINCH",
-		Sigtype::INFO => "INFO",
-		Sigtype::USR1 => "USR1",
-		Sigtype::USR2 => "USR2",
+// Named signals that exist on the target platform.
+const SIGNALS: &[(Sigtype, &str, c_int)] = &[
+	(Sigtype::HUP, "HUP", libc::SIGHUP),
+	(Sigtype::INT, "INT", libc::SIGINT),
+	(Sigtype::QUIT, "QUIT", libc::SIGQUIT),
+	(Sigtype::ILL, "ILL", libc::SIGILL),
+	(Sigtype::TRAP, "TRAP", libc::SIGTRAP),
+	(Sigtype::ABRT, "ABRT", libc::SIGABRT),
+	#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+	(Sigtype::EMT, "EMT", libc::SIGEMT),
+	(Sigtype::FPE, "FPE", libc::SIGFPE),
+	(Sigtype::KILL, "KILL", libc::SIGKILL),
+	(Sigtype::BUS, "BUS", libc::SIGBUS),
+	(Sigtype::SEGV, "SEGV", libc::SIGSEGV),
+	(Sigtype::SYS, "SYS", libc::SIGSYS),
+	(Sigtype::PIPE, "PIPE", libc::SIGPIPE),
+	(Sigtype::ALRM, "ALRM", libc::SIGALRM),
+	(Sigtype::TERM, "TERM", libc::SIGTERM),
+	(Sigtype::URG, "URG", libc::SIGURG),
+	(Sigtype::STOP, "STOP", libc::SIGSTOP),
+	(Sigtype::TSTP, "TSTP", libc::SIGTSTP),
+	(Sigtype::CONT, "CONT", libc::SIGCONT),
+	(Sigtype::CHLD, "CHLD", libc::SIGCHLD),
+	(Sigtype::TTIN, "TTIN", libc::SIGTTIN),
+	(Sigtype::TTOU, "TTOU", libc::SIGTTOU),
+	(Sigtype::IO, "IO", libc::SIGIO),
+	(Sigtype::XCPU, "XCPU", libc::SIGXCPU),
+	(Sigtype::XFSZ, "XFSZ", libc::SIGXFSZ),
+	(Sigtype::VTALRM, "VTALRM", libc::SIGVTALRM),
+	(Sigtype::PROF, "PROF", libc::SIGPROF),
+	(Sigtype::WINCH, "WINCH", libc::SIGWINCH),
+	#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+	(Sigtype::INFO, "INFO", libc::SIGINFO),
+	(Sigtype::USR1, "USR1", libc::SIGUSR1),
+	(Sigtype::USR2, "USR2", libc::SIGUSR2),
+	#[cfg(target_os = "linux")]
+	(Sigtype::STKFLT, "STKFLT", libc::SIGSTKFLT),
+	#[cfg(target_os = "linux")]
+	(Sigtype::PWR, "PWR", libc::SIGPWR),
+];
+
+impl Sigtype {
+	pub fn number(&self) -> c_int {
+		#[cfg(target_os = "linux")]
+		if let Sigtype::RT(offset) = self {
+			return libc::SIGRTMIN() + offset;
+		}
+		SIGNALS.iter()
+			.find(|(sig, _, _)| sig == self)
+			.map(|(_, _, number)| *number)
+			.unwrap_or(0)
+	}
+
+	// Accepts `TERM`, `SIGTERM`, `sigterm`, `15`, and on Linux `RTMIN+n` / `RTMAX-n`.
+	pub fn from_name(name: &str) -> Result<Sigtype, String> {
+		if let Ok(number) = name.parse::<c_int>() {
+			return Sigtype::from_number(number);
+		}
+		let upper = name.to_uppercase();
+		let short = upper.strip_prefix("SIG").unwrap_or(&upper);
+		#[cfg(target_os = "linux")]
+		if let Some(number) = Self::parse_realtime(short) {
+			return Sigtype::from_number(number?);
+		}
+		SIGNALS.iter()
+			.find(|(_, sig_name, _)| *sig_name == short)
+			.map(|(sig, _, _)| *sig)
+			.ok_or(format!("unknown signal '{}'", name))
+	}
+
+	#[cfg(target_os = "linux")]
+	fn parse_realtime(name: &str) -> Option<Result<c_int, String>> {
+		let (base, rest) = if let Some(rest) = name.strip_prefix("RTMIN") {
+			(libc::SIGRTMIN(), rest)
+		} else {
+			(libc::SIGRTMAX(), name.strip_prefix("RTMAX")?)
+		};
+		if rest.is_empty() {
+			return Some(Ok(base));
+		}
+		let offset = rest.strip_prefix(['+', '-'])
+			.and_then(|n| n.parse::<c_int>().ok())
+			.ok_or(format!("invalid real-time signal '{}'", name));
+		Some(offset.map(|offset| if rest.starts_with('-') { base - offset } else { base + offset }))
+	}
+
+	pub fn from_number(number: c_int) -> Result<Sigtype, String> {
+		#[cfg(target_os = "linux")]
+		if (libc::SIGRTMIN()..=libc::SIGRTMAX()).contains(&number) {
+			return Ok(Sigtype::RT(number - libc::SIGRTMIN()));
+		}
+		SIGNALS.iter()
+			.find(|(_, _, sig_number)| *sig_number == number)
+			.map(|(sig, _, _)| *sig)
+			.ok_or(format!("signal {} does not exist on this platform", number))
+	}
+}
+
+impl<'de> Deserialize<'de> for Sigtype {
+	fn deserialize<D>(deserializer: D) -> Result<Sigtype, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		struct SigtypeVisitor;
+
+		impl<'de> serde::de::Visitor<'de> for SigtypeVisitor {
+			type Value = Sigtype;
+
+			fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+				write!(f, "a signal name or number")
+			}
+
+			fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Sigtype, E> {
+				Sigtype::from_name(name).map_err(E::custom)
+			}
+
+			fn visit_i64<E: serde::de::Error>(self, number: i64) -> Result<Sigtype, E> {
+				let number = c_int::try_from(number).map_err(E::custom)?;
+				Sigtype::from_number(number).map_err(E::custom)
+			}
+
+			fn visit_u64<E: serde::de::Error>(self, number: u64) -> Result<Sigtype, E> {
+				let number = c_int::try_from(number).map_err(E::custom)?;
+				Sigtype::from_number(number).map_err(E::custom)
+			}
+		}
+
+		deserializer.deserialize_any(SigtypeVisitor)
+	}
+}
+
+impl std::fmt::Display for Sigtype {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		#[cfg(target_os = "linux")]
+		if let Sigtype::RT(offset) = self {
+			return write!(f, "SIGRTMIN+{}", offset);
+		}
+		match SIGNALS.iter().find(|(sig, _, _)| sig == self) {
+			Some((_, name, _)) => write!(f, "SIG{}", name),
+			None => write!(f, "{:?}", self),
+		}
+	}
+}
+
+// What the child gets as standard input. Never the supervisor's own: the shell
+// has it in raw mode and a child reading it would steal keystrokes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(try_from = "RawStdin")]
+pub enum Stdin {
+	#[default]
+	Null,
+	File(String),
+	// A pipe the shell can write to with `send` or `attach`.
+	Pipe,
+	// A pseudo-terminal for stdin, stdout and stderr, which `attach` can drive.
+	Pty,
+}
+
+// `stdin: null`, `stdin: pipe`, `stdin: pty` or `stdin: { file: path }`.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "expected null, pipe, pty or { file: path }")]
+enum RawStdin {
+	Null(()),
+	Mode(String),
+	File { file: String },
+}
+
+impl TryFrom<RawStdin> for Stdin {
+	type Error = String;
+
+	fn try_from(raw: RawStdin) -> Result<Stdin, String> {
+		match raw {
+			RawStdin::Null(()) => Ok(Stdin::Null),
+			RawStdin::File { file } => Ok(Stdin::File(file)),
+			RawStdin::Mode(mode) => match mode.as_str() {
+				"null" => Ok(Stdin::Null),
+				"pipe" => Ok(Stdin::Pipe),
+				"pty" => Ok(Stdin::Pty),
+				_ => Err(format!("unknown stdin '{}', expected null, pipe, pty or {{ file: path }}", mode)),
+			},
+		}
+	}
+}
+
+impl std::fmt::Display for Stdin {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Stdin::Null => write!(f, "null"),
+			Stdin::File(path) => write!(f, "{}", path),
+			Stdin::Pipe => write!(f, "pipe"),
+			Stdin::Pty => write!(f, "pty"),
+		}
 	}
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Cmd {
+	Line(String),
+	List(Vec<String>),
+}
+
+impl std::fmt::Display for Cmd {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Cmd::Line(line) => write!(f, "{}", line),
+			Cmd::List(list) => write!(f, "{}", shell_words::join(list)),
+		}
+	}
+}
+
+// What moves a process from Starting to Running: staying up for `starttime`, or
+// sending READY=1 on its notify socket, with `starttime` as the timeout.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Readiness {
+	#[default]
+	Starttime,
+	Notify,
+}
+
+// Delay before retrying a process that died while starting: `initial` seconds,
+// multiplied by `factor` on each attempt up to `max`, give or take `jitter` (a fraction).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct Backoff {
+	pub initial: f64,
+	pub factor: f64,
+	pub max: f64,
+	pub jitter: f64,
+}
+
+// Longest delay accepted in seconds (a day), well within what Duration and Instant hold.
+pub const MAX_DELAY: f64 = 86400.0;
+
+impl Default for Backoff {
+	fn default() -> Backoff {
+		Backoff { initial: 1.0, factor: 2.0, max: 60.0, jitter: 0.1 }
+	}
+}
+
+impl Backoff {
+	pub fn validate(&self) -> Result<(), String> {
+		if !(self.initial >= 0.0 && self.factor >= 1.0 && self.max >= self.initial) {
+			return Err("expects initial >= 0, factor >= 1 and max >= initial".to_string());
+		}
+		if !(0.0..=1.0).contains(&self.jitter) {
+			return Err(format!("jitter {} is out of range (0 to 1)", self.jitter));
+		}
+		if self.max > MAX_DELAY {
+			return Err(format!("max {} is over {} seconds", self.max, MAX_DELAY));
+		}
+		Ok(())
+	}
+
+	// Delay before the given retry, counting from 1.
+	pub fn delay(&self, attempt: u32) -> Duration {
+		let base = (self.initial * self.factor.powi(attempt.saturating_sub(1) as i32)).min(self.max);
+		// Uniform in [-1, 1): RandomState is seeded randomly on each call.
+		let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+		Duration::try_from_secs_f64((base * (1.0 + self.jitter * random)).max(0.0)).unwrap_or(Duration::from_secs(MAX_DELAY as u64))
+	}
+}
+
+// One step of a stop sequence: send `signal`, then give the process `wait` seconds to exit.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StopStep {
+	pub signal: Sigtype,
+	pub wait: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum Account {
+	Id(u32),
+	Name(String),
+}
+
+impl std::fmt::Display for Account {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Account::Id(id) => write!(f, "{}", id),
+			Account::Name(name) => write!(f, "{}", name),
+		}
+	}
+}
+
+// The configuration file: tasks by name, and supervisor-wide settings under the
+// `taskmaster:` key, which therefore cannot name a task.
+#[derive(Debug)]
+pub struct ConfigFile {
+	pub taskmaster: Settings,
+	pub tasks: BTreeMap<String, Config>,
+}
+
+// By hand rather than with #[serde(flatten)], which would lose the task name and
+// line number in error messages.
+impl<'de> Deserialize<'de> for ConfigFile {
+	fn deserialize<D>(deserializer: D) -> Result<ConfigFile, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		struct ConfigFileVisitor;
+
+		impl<'de> serde::de::Visitor<'de> for ConfigFileVisitor {
+			type Value = ConfigFile;
+
+			fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+				write!(f, "a map of task names to task configurations")
+			}
+
+			fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<ConfigFile, A::Error> {
+				let mut file = ConfigFile { taskmaster: Settings::default(), tasks: BTreeMap::new() };
+				while let Some(name) = map.next_key::<String>()? {
+					if name == "taskmaster" {
+						// Settings has no `cmd`, so a task under this name always ends up here.
+						file.taskmaster = map.next_value().map_err(|e| serde::de::Error::custom(format!(
+							"`taskmaster` is reserved for supervisor settings, rename a task with that name: {}", e)))?;
+					} else {
+						file.tasks.insert(name, map.next_value()?);
+					}
+				}
+				Ok(file)
+			}
+		}
+
+		deserializer.deserialize_map(ConfigFileVisitor)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+	// Processes allowed in Starting at once while autostarting.
+	pub max_concurrent_starts: Option<u32>,
+}
+
+// What one process of a task runs with, see Config::instance.
+#[derive(Debug)]
+pub struct Instance {
+	pub argv: Vec<String>,
+	pub workingdir: String,
+	pub env: BTreeMap<String, String>,
+	pub stdout: Option<LogConfig>,
+	pub stderr: Option<LogConfig>,
+	pub stdin: Stdin,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
-	pub cmd: String,
+	pub cmd: Cmd,
+	#[serde(default)]
+	pub shell: bool,
 	#[serde(default = "default_numprocs")]
 	pub numprocs: u32,
 	#[serde(default = "default_umask")]
@@ -109,6 +434,16 @@ pub struct Config {
 	pub workingdir: String,
 	#[serde(default = "default_autostart")]
 	pub autostart: bool,
+	// Tasks that must reach a condition before this one autostarts, and that
+	// are stopped after it on shutdown.
+	#[serde(default)]
+	pub depends_on: Vec<Dependency>,
+	// Lower starts first and stops last; dependencies still come first.
+	#[serde(default = "default_priority")]
+	pub priority: i32,
+	// Seconds between autostarting two instances of this task.
+	#[serde(default)]
+	pub start_stagger: f64,
 	#[serde(default = "default_autorestart")]
 	pub autorestart: Autorestart,
 	#[serde(default = "default_exitcodes")]
@@ -121,9 +456,200 @@ pub struct Config {
 	pub stopsignal: Sigtype,
 	#[serde(default = "default_stoptime")]
 	pub stoptime: u32,
-	pub stdout: Option<String>,
-	pub stderr: Option<String>,
+	pub stop_sequence: Option<Vec<StopStep>>,
+	#[serde(default)]
+	pub backoff: Backoff,
+	// Automatic restarts, for any reason, allowed within restart_window before the
+	// process goes Fatal. Each instance of the task has its own count.
+	pub max_restarts: Option<u32>,
+	#[serde(default = "default_restart_window")]
+	pub restart_window: u32,
+	pub cooldown: Option<u32>,
+	pub healthcheck: Option<HealthCheck>,
+	#[serde(default)]
+	pub readiness: Readiness,
+	// With readiness: notify, seconds allowed between two WATCHDOG=1.
+	pub watchdog: Option<u32>,
+	// Output line that means the process is up; starttime is then the timeout.
+	pub ready_pattern: Option<String>,
+	#[serde(default)]
+	pub alert_patterns: Vec<AlertPattern>,
+	#[serde(default)]
+	pub stopasgroup: bool,
+	#[serde(default)]
+	pub killasgroup: bool,
+	pub stdout: Option<LogConfig>,
+	pub stderr: Option<LogConfig>,
+	#[serde(default)]
+	pub redirect_stderr: bool,
+	#[serde(default)]
+	pub log_format: LogFormat,
+	#[serde(default)]
+	pub stdin: Stdin,
 	pub env: Option<BTreeMap<String, String>>,
+	pub user: Option<Account>,
+	pub group: Option<Account>,
+	pub groups: Option<Vec<Account>>,
+	pub nice: Option<i32>,
+	#[serde(default)]
+	pub rlimits: BTreeMap<Resource, RlimitValue>,
+	pub memory_max: Option<CgroupValue>,
+	pub cpu_max: Option<CgroupValue>,
+	pub pids_max: Option<CgroupValue>,
+	pub io_weight: Option<u32>,
+	#[serde(default)]
+	pub cgroup_per_process: bool,
+}
+
+impl Config {
+	pub fn validate(&self, name: &str, here: &Path) -> Result<(), Box<dyn Error>> {
+		self.instance(&Vars { task_name: name, process_num: 0, here }).map_err(|e| format!("{}: {}", name, e))?;
+		self.credentials().map_err(|e| format!("{}: {}", name, e))?;
+		self.backoff.validate().map_err(|e| format!("{}: backoff: {}", name, e))?;
+		if !(0.0..=MAX_DELAY).contains(&self.start_stagger) {
+			return Err(format!("{}: start_stagger: must be between 0 and {} seconds", name, MAX_DELAY).into());
+		}
+		if self.restart_window == 0 {
+			return Err(format!("{}: restart_window: must be at least 1 second", name).into());
+		}
+		if self.cooldown.is_some() && self.max_restarts.is_none() {
+			return Err(format!("{}: cooldown: needs max_restarts", name).into());
+		}
+		if self.watchdog.is_some() && self.readiness != Readiness::Notify {
+			return Err(format!("{}: watchdog: needs readiness: notify", name).into());
+		}
+		if self.ready_pattern.is_some() && self.readiness == Readiness::Notify {
+			return Err(format!("{}: ready_pattern: cannot be used with readiness: notify", name).into());
+		}
+		Patterns::compile(&self.ready_pattern, &self.alert_patterns).map_err(|e| format!("{}: {}", name, e))?;
+		if let Some(check) = &self.healthcheck {
+			check.probe().map_err(|e| format!("{}: healthcheck: {}", name, e))?;
+		}
+		if self.stop_sequence.as_ref().is_some_and(|steps| steps.is_empty()) {
+			return Err(format!("{}: stop_sequence: needs at least one step", name).into());
+		}
+		if let Some(nice) = self.nice {
+			if !(-20..=19).contains(&nice) {
+				return Err(format!("{}: nice: {} is out of range (-20 to 19)", name, nice).into());
+			}
+		}
+		for (resource, value) in &self.rlimits {
+			value.validate().map_err(|e| format!("{}: rlimits: {}: {}", name, resource, e))?;
+		}
+		cgroup::settings(self).map_err(|e| format!("{}: {}", name, e))?;
+		if cgroup::is_enabled(self) {
+			cgroup::validate_name(name).map_err(|e| format!("{}: {}", name, e))?;
+		}
+		if self.stdin == Stdin::Pty && (self.stderr.is_some() || self.redirect_stderr) {
+			return Err(format!("{}: stdin: a pty carries stdout and stderr together, set only stdout", name).into());
+		}
+		if self.redirect_stderr && self.stderr.is_some() {
+			return Err(format!("{}: stderr: cannot be set together with redirect_stderr", name).into());
+		}
+		Ok(())
+	}
+
+	// Whether Running waits for the process to say it is ready, with starttime as the timeout.
+	pub fn waits_for_ready(&self) -> bool {
+		self.readiness == Readiness::Notify || self.ready_pattern.is_some()
+	}
+
+	// The settings that differ between instances, with their templates expanded.
+	pub fn instance(&self, vars: &Vars) -> Result<Instance, String> {
+		let argv = self.argv(vars).map_err(|e| format!("cmd: {}", e))?;
+		let workingdir = vars.expand(&self.workingdir).map_err(|e| format!("workingdir: {}", e))?;
+		let mut env = BTreeMap::new();
+		for (key, value) in self.env.iter().flatten() {
+			env.insert(key.clone(), vars.expand(value).map_err(|e| format!("env.{}: {}", key, e))?);
+		}
+		let log = |log: &Option<LogConfig>, stream: &str| -> Result<Option<LogConfig>, String> {
+			let Some(log) = log else { return Ok(None) };
+			let path = vars.expand(&log.path).map_err(|e| format!("{}: {}", stream, e))?;
+			Ok(Some(LogConfig { path, ..log.clone() }))
+		};
+		let stdin = match &self.stdin {
+			Stdin::File(path) => Stdin::File(vars.expand(path).map_err(|e| format!("stdin: {}", e))?),
+			stdin => stdin.clone(),
+		};
+		Ok(Instance { argv, workingdir, env, stdout: log(&self.stdout, "stdout")?, stderr: log(&self.stderr, "stderr")?, stdin })
+	}
+
+	// Signals to go through on stop before the final SIGKILL; stopsignal/stoptime by default.
+	pub fn stop_steps(&self) -> Vec<StopStep> {
+		match &self.stop_sequence {
+			Some(steps) => steps.clone(),
+			None => vec![StopStep { signal: self.stopsignal, wait: self.stoptime }],
+		}
+	}
+
+	pub fn credentials(&self) -> Result<Option<Credentials>, String> {
+		Credentials::resolve(&self.user, &self.group, &self.groups)
+	}
+
+	// Program and arguments to exec, honoring the `shell` option. Words are expanded
+	// after splitting, so a {here} containing spaces stays a single argument.
+	fn argv(&self, vars: &Vars) -> Result<Vec<String>, String> {
+		let words = match (&self.cmd, self.shell) {
+			(Cmd::Line(line), true) => return Ok(vec!["/bin/sh".to_string(), "-c".to_string(), vars.expand(line)?]),
+			(Cmd::List(_), true) => return Err("shell: true expects cmd to be a string".to_string()),
+			(Cmd::Line(line), false) => shell_words::split(line).map_err(|e| e.to_string())?,
+			(Cmd::List(list), false) => list.clone(),
+		};
+		let argv = words.iter().map(|word| vars.expand(word)).collect::<Result<Vec<String>, String>>()?;
+		match argv.first() {
+			Some(program) if !program.is_empty() => Ok(argv),
+			_ => Err("Command is empty".to_string()),
+		}
+	}
+}
+
+// Each log file has a single writer: two sinks appending to the same file would
+// garble each other's lines and fight over rotation.
+// Instances of one task may share a file: they then share its sink.
+pub fn validate_log_paths(configs: &BTreeMap<String, Config>, here: &Path) -> Result<(), Box<dyn Error>> {
+	let mut owners: HashMap<PathBuf, (&str, &str)> = HashMap::new();
+	for (name, config) in configs {
+		for process_num in 0..config.numprocs {
+			let Ok(instance) = config.instance(&Vars { task_name: name, process_num, here }) else { continue };
+			for (stream, log) in [("stdout", &instance.stdout), ("stderr", &instance.stderr)] {
+				let Some(log) = log else { continue };
+				claim_log_path(&mut owners, name, stream, &log.path)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+fn claim_log_path<'a>(owners: &mut HashMap<PathBuf, (&'a str, &'a str)>, name: &'a str, stream: &'a str, path: &str) -> Result<(), Box<dyn Error>> {
+	let absolute = std::path::absolute(path).map_err(|e| format!("{}.{}: {}: {}", name, stream, path, e))?;
+	// Resolve the directory (symlinks, `..`) but not the file, which may not exist yet.
+	let resolved = match (absolute.parent().and_then(|dir| dir.canonicalize().ok()), absolute.file_name()) {
+		(Some(dir), Some(file)) => dir.join(file),
+		_ => absolute,
+	};
+	match owners.insert(resolved, (name, stream)) {
+		Some((owner, owner_stream)) if (owner, owner_stream) != (name, stream) => {
+			let hint = if owner == name { " (use redirect_stderr to merge the streams)" } else { "" };
+			Err(format!("{}.{}: {} is already used by {}.{}{}", name, stream, path, owner, owner_stream, hint).into())
+		}
+		_ => Ok(()),
+	}
+}
+
+// Bytes, or a number with a K/M/G/T suffix ("512M").
+pub fn parse_size(size: &str) -> Result<u64, String> {
+	let size = size.trim();
+	let (digits, shift) = match size.chars().last() {
+		Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+		Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+		Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+		Some('T') | Some('t') => (&size[..size.len() - 1], 40),
+		_ => (size, 0),
+	};
+	digits.trim().parse::<u64>()
+		.ok()
+		.and_then(|n| n.checked_mul(1 << shift))
+		.ok_or(format!("invalid size '{}'", size))
 }
 
 fn umask_deserializer<'de, D>(deserializer: D) -> Result<u32, D::Error>
@@ -151,6 +677,10 @@ fn default_autostart() -> bool {
 	true
 }
 
+fn default_priority() -> i32 {
+	999
+}
+
 fn default_exitcodes() -> Vec<i32> {
 	vec![0]
 }
@@ -171,38 +701,197 @@ fn default_stopsignal() -> Sigtype {
 	Sigtype::TERM
 }
 
+fn default_restart_window() -> u32 {
+	60
+}
+
 fn default_stoptime() -> u32 {
 	10
 }
 
-pub fn print_config(tasks: &BTreeMap<String, Config>) {
-	for (name, task) in tasks {
-		println!("App: {}", name);
-		println!("\tStart Command: {}", task.cmd);
-		println!("\tNumber of Processes: {}", task.numprocs);
-		println!("\tUmask: {}", task.umask);
-		println!("\tWorking Directory: {}", task.workingdir);
-		println!("\tAutostart: {}", task.autostart);
-		println!("\tAutorestart: {:?}", task.autorestart);
-		println!("\tExitcodes:");
-		for code in &task.exitcodes {
-			println!("\t\t- {}", code);
-		}
-		println!("\tStart Retries: {}", task.startretries);
-		println!("\tStart Time: {}", task.starttime);
-		println!("\tStop Signal: {:?}", task.stopsignal);
-		println!("\tStop Time: {}", task.stoptime);
-		if let Some(stdout) = &task.stdout {
-			println!("\tNormal Output: {}", stdout);
-		}
-		if let Some(stderr) = &task.stderr {
-			println!("\tError Output: {}", stderr);
-		}
-		if let Some(env) = &task.env {
-			println!("\tEnv: ");
-			for (key, value) in env {
-				println!("\t\t- {}: {}", key, value);
+pub fn print_config(name: &str, task: &Config) {
+	println!("App: {}", name);
+	println!("\tStart Command: {}", task.cmd);
+	println!("\tShell: {}", task.shell);
+	println!("\tNumber of Processes: {}", task.numprocs);
+	println!("\tUmask: {:03o}", task.umask);
+	println!("\tWorking Directory: {}", task.workingdir);
+	println!("\tAutostart: {}", task.autostart);
+	println!("\tPriority: {}", task.priority);
+	if task.start_stagger > 0.0 {
+		println!("\tStart Stagger: {}s", task.start_stagger);
+	}
+	if !task.depends_on.is_empty() {
+		println!("\tDepends On:");
+		for dep in &task.depends_on {
+			println!("\t\t- {}", dep);
+		}
+	}
+	println!("\tAutorestart: {:?}", task.autorestart);
+	println!("\tExitcodes:");
+	for code in &task.exitcodes {
+		println!("\t\t- {}", code);
+	}
+	println!("\tStart Retries: {}", task.startretries);
+	println!("\tStart Time: {}", task.starttime);
+	if task.readiness == Readiness::Notify {
+		println!("\tReadiness: notify (starttime is the timeout)");
+		if let Some(watchdog) = task.watchdog {
+			println!("\tWatchdog: {}s", watchdog);
+		}
+	}
+	if let Some(pattern) = &task.ready_pattern {
+		println!("\tReady Pattern: /{}/ (starttime is the timeout)", pattern);
+	}
+	if !task.alert_patterns.is_empty() {
+		println!("\tAlert Patterns:");
+		for alert in &task.alert_patterns {
+			println!("\t\t- {}", alert);
+		}
+	}
+	println!("\tBackoff: {}s x{} up to {}s (jitter {})", task.backoff.initial, task.backoff.factor, task.backoff.max, task.backoff.jitter);
+	if let Some(max) = task.max_restarts {
+		println!("\tMax Restarts: {} within {}s", max, task.restart_window);
+		if let Some(cooldown) = task.cooldown {
+			println!("\tCooldown: {}s", cooldown);
+		}
+	}
+	if let Some(check) = &task.healthcheck {
+		if let Ok(probe) = check.probe() {
+			println!("\tHealth Check: {} every {}s, timeout {}s, unhealthy after {} failures{}",
+				probe, check.interval, check.timeout, check.failures, if check.restart { ", then restart" } else { "" });
+		}
+	}
+	match &task.stop_sequence {
+		Some(steps) => {
+			println!("\tStop Sequence:");
+			for step in steps {
+				println!("\t\t- {} then wait {}s", step.signal, step.wait);
 			}
 		}
+		None => {
+			println!("\tStop Signal: {}", task.stopsignal);
+			println!("\tStop Time: {}", task.stoptime);
+		}
+	}
+	println!("\tStop As Group: {}", task.stopasgroup);
+	println!("\tKill As Group: {}", task.killasgroup || task.stopasgroup);
+	if let Some(stdout) = &task.stdout {
+		println!("\tNormal Output: {}", stdout);
+	}
+	if let Some(stderr) = &task.stderr {
+		println!("\tError Output: {}", stderr);
+	}
+	if task.redirect_stderr {
+		println!("\tError Output: redirected to normal output");
+	}
+	if task.log_format != LogFormat::Raw {
+		println!("\tLog Format: {}", task.log_format);
+	}
+	if task.stdin != Stdin::Null {
+		println!("\tStdin: {}", task.stdin);
+	}
+	if let Some(nice) = task.nice {
+		println!("\tNice: {}", nice);
+	}
+	if let Some(user) = &task.user {
+		println!("\tUser: {}", user);
+	}
+	if let Some(group) = &task.group {
+		println!("\tGroup: {}", group);
+	}
+	if let Some(groups) = &task.groups {
+		println!("\tGroups:");
+		for group in groups {
+			println!("\t\t- {}", group);
+		}
+	}
+	if !task.rlimits.is_empty() {
+		println!("\tRlimits:");
+		for (resource, value) in &task.rlimits {
+			println!("\t\t- {}: soft {}, hard {}", resource, value.soft(), value.hard());
+		}
+	}
+	if cgroup::is_enabled(task) {
+		println!("\tCgroup: {}", if task.cgroup_per_process { "per process" } else { "per task" });
+		if let Some(memory_max) = &task.memory_max {
+			println!("\t\t- memory_max: {}", memory_max);
+		}
+		if let Some(cpu_max) = &task.cpu_max {
+			println!("\t\t- cpu_max: {}", cpu_max);
+		}
+		if let Some(pids_max) = &task.pids_max {
+			println!("\t\t- pids_max: {}", pids_max);
+		}
+		if let Some(io_weight) = task.io_weight {
+			println!("\t\t- io_weight: {}", io_weight);
+		}
+	}
+	if let Some(env) = &task.env {
+		println!("\tEnv: ");
+		for (key, value) in env {
+			println!("\t\t- {}: {}", key, value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn signal_names_with_or_without_sig_prefix() {
+		assert_eq!(Sigtype::from_name("TERM"), Ok(Sigtype::TERM));
+		assert_eq!(Sigtype::from_name("SIGTERM"), Ok(Sigtype::TERM));
+		assert_eq!(Sigtype::from_name("sigusr1"), Ok(Sigtype::USR1));
+		assert!(Sigtype::from_name("SIGNOPE").is_err());
+		assert!(Sigtype::from_name("").is_err());
+	}
+
+	#[test]
+	fn signal_numbers() {
+		assert_eq!(Sigtype::from_name("9"), Ok(Sigtype::KILL));
+		assert_eq!(Sigtype::from_name(&libc::SIGHUP.to_string()), Ok(Sigtype::HUP));
+		assert_eq!(Sigtype::KILL.number(), libc::SIGKILL);
+		assert!(Sigtype::from_name("0").is_err());
+		assert!(Sigtype::from_name("-1").is_err());
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn realtime_signals() {
+		let span = libc::SIGRTMAX() - libc::SIGRTMIN();
+		assert_eq!(Sigtype::from_name("RTMIN"), Ok(Sigtype::RT(0)));
+		assert_eq!(Sigtype::from_name("SIGRTMIN+3"), Ok(Sigtype::RT(3)));
+		assert_eq!(Sigtype::from_name("rtmax"), Ok(Sigtype::RT(span)));
+		assert_eq!(Sigtype::from_name("RTMAX-2"), Ok(Sigtype::RT(span - 2)));
+		assert_eq!(Sigtype::RT(3).number(), libc::SIGRTMIN() + 3);
+		assert_eq!(Sigtype::RT(3).to_string(), "SIGRTMIN+3");
+		assert!(Sigtype::from_name("RTMIN-1").is_err());
+		assert!(Sigtype::from_name("RTMAX+1").is_err());
+		assert!(Sigtype::from_name("RTMIN+x").is_err());
+	}
+
+	#[test]
+	fn sizes_with_suffixes() {
+		assert_eq!(parse_size("512"), Ok(512));
+		assert_eq!(parse_size("10K"), Ok(10 * 1024));
+		assert_eq!(parse_size("512m"), Ok(512 << 20));
+		assert_eq!(parse_size(" 2 G "), Ok(2 << 30));
+		assert_eq!(parse_size("1T"), Ok(1 << 40));
+		assert!(parse_size("").is_err());
+		assert!(parse_size("M").is_err());
+		assert!(parse_size("1.5M").is_err());
+		assert!(parse_size("-1K").is_err());
+		assert!(parse_size("99999999999T").is_err());
+	}
+
+	#[test]
+	fn taskmaster_key_holds_settings() {
+		let file: ConfigFile = serde_yaml::from_str("taskmaster: { max_concurrent_starts: 2 }\na: { cmd: x }").unwrap();
+		assert_eq!(file.taskmaster.max_concurrent_starts, Some(2));
+		assert_eq!(file.tasks.keys().collect::<Vec<_>>(), vec!["a"]);
+		let error = serde_yaml::from_str::<ConfigFile>("taskmaster: { cmd: x }").unwrap_err().to_string();
+		assert!(error.starts_with("`taskmaster` is reserved for supervisor settings"), "{}", error);
 	}
 }
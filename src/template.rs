@@ -0,0 +1,84 @@
+use std::{env, path::{Path, PathBuf}};
+
+// Values available to `{...}` placeholders for one instance of a task.
+pub struct Vars<'a> {
+	pub task_name: &'a str,
+	pub process_num: u32,
+	// Directory of the configuration file.
+	pub here: &'a Path,
+}
+
+impl Vars<'_> {
+	// Replaces {process_num}, {task_name}, {here} and {env.X}. Any other braces are
+	// left alone so shell snippets like ${HOME} or {a,b} pass through untouched.
+	pub fn expand(&self, text: &str) -> Result<String, String> {
+		let mut expanded = String::with_capacity(text.len());
+		let mut rest = text;
+		while let Some(start) = rest.find('{') {
+			expanded.push_str(&rest[..start]);
+			rest = &rest[start..];
+			let Some(end) = rest.find('}') else { break };
+			if rest[1..end].contains('{') {
+				expanded.push('{');
+				rest = &rest[1..];
+				continue;
+			}
+			match self.lookup(&rest[1..end])? {
+				Some(value) => expanded.push_str(&value),
+				None => expanded.push_str(&rest[..=end]),
+			}
+			rest = &rest[end + 1..];
+		}
+		expanded.push_str(rest);
+		Ok(expanded)
+	}
+
+	fn lookup(&self, name: &str) -> Result<Option<String>, String> {
+		let value = match name {
+			"process_num" => self.process_num.to_string(),
+			"task_name" => self.task_name.to_string(),
+			"here" => self.here.display().to_string(),
+			_ => match name.strip_prefix("env.") {
+				Some(var) => env::var(var).map_err(|_| format!("{{{}}}: environment variable {} is not set", name, var))?,
+				None => return Ok(None),
+			},
+		};
+		Ok(Some(value))
+	}
+}
+
+// `{here}` for a configuration file: its directory as an absolute path.
+pub fn config_dir(path: &Path) -> PathBuf {
+	let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+	absolute.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn vars() -> Vars<'static> {
+		Vars { task_name: "web", process_num: 2, here: Path::new("/etc/tm") }
+	}
+
+	#[test]
+	fn known_placeholders() {
+		assert_eq!(vars().expand("{task_name}-{process_num}.log"), Ok("web-2.log".to_string()));
+		assert_eq!(vars().expand("{here}/run"), Ok("/etc/tm/run".to_string()));
+		assert_eq!(vars().expand("{env.PATH}"), Ok(env::var("PATH").unwrap()));
+	}
+
+	#[test]
+	fn other_braces_are_left_alone() {
+		assert_eq!(vars().expand("${HOME} {a,b} {}"), Ok("${HOME} {a,b} {}".to_string()));
+		assert_eq!(vars().expand("{{process_num}}"), Ok("{2}".to_string()));
+		assert_eq!(vars().expand("open { and }"), Ok("open { and }".to_string()));
+		assert_eq!(vars().expand("unclosed {task_name"), Ok("unclosed {task_name".to_string()));
+	}
+
+	#[test]
+	fn missing_environment_variable() {
+		let error = vars().expand("{env.TASKMASTER_TEST_UNSET}").unwrap_err();
+		assert!(error.contains("TASKMASTER_TEST_UNSET is not set"), "{}", error);
+	}
+}
@@ -1,9 +1,9 @@
-use std::{io::{self, Read, stdout, Write}, sync::mpsc::Sender, process::exit, ffi::c_char};
+use std::{io::{self, Read, Write}, sync::mpsc::{self, Sender}};
 
-use crate::monitor::CommandName;
+use crate::{event, monitor::CommandName, output::Stream};
 use std::os::unix::io::AsRawFd;
 use std::mem;
-use libc::{self, c_int, tcgetattr, tcsetattr, TCSANOW, STDIN_FILENO, termios, ECHO, ICANON, ISIG, VMIN, VTIME, VINTR, VEOF, INPCK, ISTRIP, IXON, BRKINT, CS8};
+use libc::{self, tcgetattr, tcsetattr, TCSANOW, termios, ECHO, ICANON, ISIG, INPCK, ISTRIP, IXON, BRKINT, CS8};
 
 const ENTER: char = '\n';
 const BACKSPACE: char = '\x7f';
@@ -11,20 +11,41 @@ const TAB: char = '\t';
 const ARROW: char = '\x1B';
 const CTRLC: char = '\x03';
 const CTRL_BACK: char = '\x1c';
-const LEFT: &'static str = "[D";
-const RIGHT: &'static str = "[C";
-const UP: &'static str = "[A";
-const DOWN: &'static str = "[B";
+// Ctrl-], leaves `attach`.
+const DETACH: u8 = 0x1d;
+const LEFT: &str = "[D";
+const RIGHT: &str = "[C";
+const UP: &str = "[A";
+const DOWN: &str = "[B";
 
 
 pub struct TermInput {
 	pub cmd_name: CommandName,
-	pub args: Vec<ProcessArg>
+	pub args: Vec<ProcessArg>,
+	pub tail: Option<TailOptions>,
+	// Bytes for the process's stdin.
+	pub input: Vec<u8>,
+	// Answered by the monitor with whether the command succeeded.
+	pub reply: Option<Sender<bool>>,
 }
 
 impl TermInput {
 	pub fn new(cmd_name: CommandName, args: Vec<ProcessArg>) -> TermInput {
-		TermInput { cmd_name, args }
+		TermInput { cmd_name, args, tail: None, input: vec![], reply: None }
+	}
+}
+
+// `tail task[:id] [stdout|stderr] [-n N] [-f]`
+#[derive(Clone, Copy, Debug)]
+pub struct TailOptions {
+	pub stream: Option<Stream>,
+	pub lines: usize,
+	pub follow: bool,
+}
+
+impl Default for TailOptions {
+	fn default() -> TailOptions {
+		TailOptions { stream: None, lines: 10, follow: false }
 	}
 }
 
@@ -84,15 +105,15 @@ impl Terminal {
 				match c {
 					CTRLC | CTRL_BACK => {
 						Self::clear_line();
-						self.sender.send(TermInput::new(CommandName::KILL, vec![])).ok();
+						Self::send(&self.sender, TermInput::new(CommandName::KILL, vec![]));
 					}
 					TAB => {
 						// Tab key pressed, complete the current word
 						if suggest_word.is_none() {
 							suggest_word = Some(word.clone());
 						}
-						let suggestion = suggest_word.as_ref().map(|s| s.as_str()).unwrap_or(word.as_str());
-						let completions = Self::get_completions(&suggestion);
+						let suggestion = suggest_word.as_deref().unwrap_or(word.as_str());
+						let completions = Self::get_completions(suggestion);
 						if completions.len() == 1 {
 							// Only one completion, replace the current word with it
 							word = completions[0].clone();
@@ -105,7 +126,7 @@ impl Terminal {
 								for completion in completions {
 									print!("{}		", completion);
 								}
-								print!("\n");
+								println!();
 								print!("{}", word);
 							} else {
 								if tab_index >= completions.len() {
@@ -169,8 +190,8 @@ impl Terminal {
 								UP => {
 									if !self.history.is_empty() && index_history != 0 {
 										let histo_at = self.history.get(index_history - 1).unwrap();
-										Self::clear_line_and_print(&histo_at);
-										if saved_word == None {
+										Self::clear_line_and_print(histo_at);
+										if saved_word.is_none() {
 											saved_word = Some(word.clone());
 										}
 										word = histo_at.clone();
@@ -185,7 +206,7 @@ impl Terminal {
 										if index_history + 1 < self.history.len() {
 											index_history += 1;
 											let histo_at = self.history.get(index_history).unwrap();
-											Self::clear_line_and_print(&histo_at);
+											Self::clear_line_and_print(histo_at);
 											word = histo_at.clone();
 											cursor_pos = word.len();
 										} else if let Some(saved) = saved_word{
@@ -216,20 +237,24 @@ impl Terminal {
 	}
 
 	fn get_completions(word: &str) -> Vec<String> {
-		let commands = vec![
+		let commands = [
 			String::from("status"),
+			String::from("info"),
 			String::from("start"),
 			String::from("stop"),
 			String::from("shutdown"),
 			String::from("update"),
 			String::from("restart"),
 			String::from("help"),
+			String::from("tail"),
+			String::from("attach"),
+			String::from("send"),
 		];
 	
 		commands
 			.iter()
 			.filter(|&s| s.starts_with(word))
-			.map(|s| s.clone())
+			.cloned()
 			.collect()
 	}
 
@@ -254,7 +279,7 @@ impl Terminal {
 		ProcessArg { name, id }
 	}
 
-	fn parse_args(input: &Vec<&str>) -> (Option<String>, Vec<ProcessArg>) {
+	fn parse_args(input: &[&str]) -> (Option<String>, Vec<ProcessArg>) {
 		let mut i = 0;
 		let mut cmd: Option<String> = None;
 		let mut args: Vec<ProcessArg> = vec![];
@@ -269,8 +294,58 @@ impl Terminal {
 		(cmd, args)
 	}
 	
-	fn check_input(input: String, sender: &Sender<TermInput>) {
-		let input: Vec<&str> = input.split_whitespace().collect();
+	fn parse_tail(input: &[&str]) -> Result<(TailOptions, Vec<ProcessArg>), String> {
+		let mut options = TailOptions::default();
+		let mut args: Vec<ProcessArg> = vec![];
+		let mut words = input.iter().skip(1);
+		while let Some(word) = words.next() {
+			match *word {
+				"-f" => options.follow = true,
+				"-n" => {
+					let count = words.next().ok_or("-n expects a number of lines")?;
+					options.lines = count.parse().map_err(|_| format!("invalid number of lines '{}'", count))?;
+				}
+				"stdout" => options.stream = Some(Stream::Stdout),
+				"stderr" => options.stream = Some(Stream::Stderr),
+				_ => args.push(Self::get_task_and_arg(word)),
+			}
+		}
+		Ok((options, args))
+	}
+
+	// Blocks until a key is pressed, then stops following.
+	fn wait_for_key(sender: &Sender<TermInput>) {
+		let mut buf = [0; 16];
+		let _ = io::stdin().read(&mut buf);
+		Self::send(sender, TermInput::new(CommandName::UNFOLLOW, vec![]));
+	}
+
+	// Forwards keystrokes to the process until the detach key. Nothing is
+	// interpreted here: Ctrl-C and friends reach the child's terminal.
+	fn attach(sender: &Sender<TermInput>, arg: ProcessArg) {
+		let (reply, attached) = mpsc::channel();
+		Self::send(sender, TermInput { reply: Some(reply), ..TermInput::new(CommandName::ATTACH, vec![arg.clone()]) });
+		if attached.recv() != Ok(true) {
+			return;
+		}
+		let mut buf = [0; 1024];
+		loop {
+			let n = io::stdin().read(&mut buf).unwrap_or(0);
+			let input = &buf[..n];
+			let detach = input.iter().position(|&b| b == DETACH);
+			let input = &input[..detach.unwrap_or(n)];
+			if !input.is_empty() {
+				Self::send(sender, TermInput { input: input.to_vec(), ..TermInput::new(CommandName::INPUT, vec![arg.clone()]) });
+			}
+			if n == 0 || detach.is_some() {
+				break;
+			}
+		}
+		Self::send(sender, TermInput::new(CommandName::DETACH, vec![arg]));
+	}
+
+	fn check_input(line: String, sender: &Sender<TermInput>) {
+		let input: Vec<&str> = line.split_whitespace().collect();
 		if input.is_empty() {
 			return;
 		}
@@ -283,33 +358,73 @@ impl Terminal {
 					if args.is_empty() {
 						return Self::task_missing(&cmd);
 					}
-					sender.send(TermInput::new(CommandName::START, args)).ok();
+					Self::send(sender, TermInput::new(CommandName::START, args));
 				}
 				"stop" => {
 					if args.is_empty() {
 						return Self::task_missing(&cmd);
 					}
-					sender.send(TermInput::new(CommandName::STOP, args)).ok();
+					Self::send(sender, TermInput::new(CommandName::STOP, args));
 				}
 				"restart" => {
 					if args.is_empty() {
 						return Self::task_missing(&cmd);
 					}
-					sender.send(TermInput::new(CommandName::RESTART, args)).ok();
+					Self::send(sender, TermInput::new(CommandName::RESTART, args));
 				}
 				"status" => {
-					sender.send(TermInput::new(CommandName::STATUS, args)).ok();
+					Self::send(sender, TermInput::new(CommandName::STATUS, args));
+				}
+				"info" => {
+					if args.is_empty() {
+						return Self::task_missing(&cmd);
+					}
+					Self::send(sender, TermInput::new(CommandName::INFO, args));
+				}
+				"tail" => {
+					let (options, args) = match Self::parse_tail(&input) {
+						Ok(parsed) => parsed,
+						Err(e) => return println!("{}", e),
+					};
+					if args.is_empty() {
+						return Self::task_missing(&cmd);
+					}
+					let follow = options.follow;
+					Self::send(sender, TermInput { tail: Some(options), ..TermInput::new(CommandName::TAIL, args) });
+					if follow {
+						Self::wait_for_key(sender);
+					}
+				}
+				"attach" => {
+					if args.len() != 1 {
+						return println!("Usage: attach task[:id]");
+					}
+					Self::attach(sender, args[0].clone());
+				}
+				"send" => {
+					let words = match shell_words::split(&line) {
+						Ok(words) => words,
+						Err(e) => return println!("{}", e),
+					};
+					if words.len() != 3 {
+						return println!("Usage: send task[:id] \"text\"");
+					}
+					let input = format!("{}\n", words[2]).into_bytes();
+					Self::send(sender, TermInput { input, ..TermInput::new(CommandName::INPUT, vec![Self::get_task_and_arg(&words[1])]) });
 				}
 				"update" => {
-					sender.send(TermInput::new(CommandName::UPDATE, args)).ok();
+					Self::send(sender, TermInput::new(CommandName::UPDATE, args));
 				}
 				"help" => {
 					println!("Here are the command you can use:");
 					println!("===================================");
-					println!("start    stop    restart    status");
+					println!("start    stop    restart    status    info");
+					println!("tail task[:id] [stdout|stderr] [-n N] [-f]");
+					println!("attach task[:id]    (Ctrl-] to detach)");
+					println!("send task[:id] \"text\"");
 				}
 				"shutdown" => {
-					sender.send(TermInput::new(CommandName::SHUTDOWN, args)).ok();
+					Self::send(sender, TermInput::new(CommandName::SHUTDOWN, args));
 				}
 				_ => {
 					println!("Command not found");
@@ -320,6 +435,12 @@ impl Terminal {
 		}
 	}
 	
+	fn send(sender: &Sender<TermInput>, input: TermInput) {
+		if sender.send(input).is_ok() {
+			event::wake();
+		}
+	}
+
 	fn clear_line() {
 		print!("\r\x1B[2K");
 	}